@@ -0,0 +1,274 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs::{self, OpenOptions},
+    future::Future,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use chrono::Local;
+use common::{Event, EventKind};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::CONFIG, folders::SyncFolder, util::SYNC_NOW};
+
+/// The log is rotated to `<error_logs>.1` once it grows past this
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+/// Rotated logs kept besides the current one
+const ROTATED_LOGS: usize = 3;
+/// Entries kept in memory for the UI and the control API
+const RECENT_KEPT: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Synced,
+    Failed,
+}
+
+/// How to try a failed operation again on the next sync cycle
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Retry {
+    /// Journal the local change again
+    Send(Event),
+    /// Compare the whole folder with the server again, for changes coming from it
+    Reconcile,
+}
+
+/// One line of the activity log
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Activity {
+    /// Only unique within this run, entries read back from the log are numbered again
+    #[serde(default)]
+    pub id: u64,
+    pub time: String,
+    pub outcome: Outcome,
+    /// `Upload`, `Download`, `Delete`, ... or what failed outside of a file transfer
+    pub operation: String,
+    /// Local folder of the pair, `None` for requests not tied to one
+    pub folder: Option<PathBuf>,
+    pub path: Option<String>,
+    /// Status code the server answered with, `None` if it couldn't be reached
+    pub response: Option<String>,
+    /// The request or file operation that failed underneath
+    pub detail: Option<String>,
+    pub retry: Option<Retry>,
+}
+
+/// What went wrong during an [`attempt`]
+#[derive(Clone, Debug, Default)]
+pub struct Failure {
+    detail: Option<String>,
+    response: Option<String>,
+}
+
+tokio::task_local! {
+    static FAILURE: RefCell<Option<Failure>>;
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Latest entries, oldest first, starting with the tail of the log from earlier runs
+static RECENT: Lazy<Mutex<VecDeque<Activity>>> = Lazy::new(|| {
+    let content = fs::read_to_string(&CONFIG.error_logs).unwrap_or_default();
+    let mut recent: VecDeque<Activity> = content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    while recent.len() > RECENT_KEPT {
+        recent.pop_front();
+    }
+    for entry in recent.iter_mut() {
+        entry.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    }
+    Mutex::new(recent)
+});
+
+/// Retries asked for from the UI or the control API, picked up by the folder's next sync
+static RETRIES: Mutex<Vec<(PathBuf, Retry)>> = Mutex::new(Vec::new());
+
+/// Runs a file operation, holding back the failures reported inside it so they end up in the
+/// operation's own entry instead of one each
+pub async fn attempt<T>(operation: impl Future<Output = T>) -> (T, Option<Failure>) {
+    FAILURE
+        .scope(RefCell::new(None), async {
+            let result = operation.await;
+            let failure = FAILURE.with(|failure| failure.borrow_mut().take());
+            (result, failure)
+        })
+        .await
+}
+
+/// A failed request or file operation. Outside of an [`attempt`] it's logged straight away.
+pub fn failed(detail: &str, path: Option<&str>, response: Option<&str>) {
+    let held = FAILURE.try_with(|failure| {
+        *failure.borrow_mut() = Some(Failure {
+            detail: Some(detail.to_string()),
+            response: response.map(str::to_string),
+        });
+    });
+    if held.is_ok() {
+        return;
+    }
+
+    record(Activity {
+        id: 0,
+        time: now(),
+        outcome: Outcome::Failed,
+        operation: detail.to_string(),
+        folder: None,
+        path: path.map(str::to_string),
+        response: response.map(str::to_string),
+        detail: None,
+        retry: None,
+    });
+}
+
+pub fn synced(folder: &SyncFolder, operation: &str, path: &str) {
+    record(Activity {
+        id: 0,
+        time: now(),
+        outcome: Outcome::Synced,
+        operation: operation.to_string(),
+        folder: Some(folder.local.clone()),
+        path: Some(path.to_string()),
+        response: None,
+        detail: None,
+        retry: None,
+    });
+}
+
+/// A file operation on the pair that failed, with what went wrong in it if anything was reported
+pub fn failed_in(
+    folder: &SyncFolder,
+    operation: &str,
+    path: &str,
+    failure: Option<Failure>,
+    retry: Retry,
+) {
+    let failure = failure.unwrap_or_default();
+    record(Activity {
+        id: 0,
+        time: now(),
+        outcome: Outcome::Failed,
+        operation: operation.to_string(),
+        folder: Some(folder.local.clone()),
+        path: Some(path.to_string()),
+        response: failure.response,
+        detail: failure.detail,
+        retry: Some(retry),
+    });
+}
+
+/// A change moved to the dead letter file after failing `attempts` times in a row
+pub fn gave_up(folder: &SyncFolder, event: &Event, attempts: u32, retry: Retry) {
+    record(Activity {
+        id: 0,
+        time: now(),
+        outcome: Outcome::Failed,
+        operation: "Gave up on".to_string(),
+        folder: Some(folder.local.clone()),
+        path: Some(event.to_string()),
+        response: None,
+        detail: Some(format!("{} failed attempts", attempts)),
+        retry: Some(retry),
+    });
+}
+
+/// The retry for a failed upload, delete or rename of the local `kind`
+pub fn resend(kind: EventKind) -> Retry {
+    Retry::Send(Event::new(kind))
+}
+
+/// Latest entries, newest first
+pub fn recent() -> Vec<Activity> {
+    RECENT.lock().unwrap().iter().rev().cloned().collect()
+}
+
+/// Latest failures, oldest first
+pub fn failures() -> Vec<Activity> {
+    RECENT
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry.outcome == Outcome::Failed)
+        .cloned()
+        .collect()
+}
+
+/// Queues the entry's retry for the next sync cycle and starts one, false if it can't be retried.
+/// Each entry is only retried once, a retry that fails again gets an entry of its own.
+pub fn retry(id: u64) -> bool {
+    let mut recent = RECENT.lock().unwrap();
+    let Some(entry) = recent.iter_mut().find(|entry| entry.id == id) else {
+        return false;
+    };
+    let (Some(folder), Some(retry)) = (entry.folder.clone(), entry.retry.take()) else {
+        return false;
+    };
+    drop(recent);
+
+    RETRIES.lock().unwrap().push((folder, retry));
+    SYNC_NOW.notify_one();
+    true
+}
+
+/// Retries queued for the pair, removing them from the queue
+pub fn take_retries(folder: &SyncFolder) -> Vec<Retry> {
+    let mut retries = RETRIES.lock().unwrap();
+    let (taken, left) = retries.drain(..).partition(|(local, _)| *local == folder.local);
+    *retries = left;
+    taken.into_iter().map(|(_, retry)| retry).collect()
+}
+
+fn now() -> String {
+    Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn record(mut entry: Activity) {
+    // Held while appending too, so the log is read back before the first entry of this run and
+    // lines from different threads don't interleave
+    let mut recent = RECENT.lock().unwrap();
+    entry.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    append(Path::new(&CONFIG.error_logs), &entry);
+
+    if recent.len() == RECENT_KEPT {
+        recent.pop_front();
+    }
+    recent.push_back(entry);
+}
+
+/// Appends the entry as a JSON line, rotating the log first when it's full
+fn append(path: &Path, entry: &Activity) {
+    if fs::metadata(path).is_ok_and(|m| m.len() > MAX_LOG_SIZE) {
+        rotate(path);
+    }
+
+    let Ok(line) = serde_json::to_string(entry) else {
+        return;
+    };
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        let _ = writeln!(file, "{}", line);
+    }
+}
+
+/// `log` becomes `log.1`, `log.1` becomes `log.2` and so on, dropping the oldest
+fn rotate(path: &Path) {
+    let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+
+    let _ = fs::remove_file(rotated(ROTATED_LOGS));
+    for n in (1..ROTATED_LOGS).rev() {
+        let _ = fs::rename(rotated(n), rotated(n + 1));
+    }
+    let _ = fs::rename(path, rotated(1));
+}
@@ -0,0 +1,89 @@
+use std::sync::Mutex;
+
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde_json::{Value, json};
+use tungstenite::{
+    client::IntoClientRequest,
+    handshake::client::Request,
+    http::{HeaderValue, header::AUTHORIZATION},
+};
+
+use crate::{config::CONFIG, connection::write_err_logs, server};
+
+/// Bearer token issued by the website backend, shared by every request to the sync server
+static TOKEN: Mutex<Option<String>> = Mutex::new(None);
+
+async fn login() -> Option<String> {
+    let res = Client::new()
+        .post(format!("{}/login", CONFIG.auth_url))
+        .json(&json!({"username": CONFIG.username, "password": CONFIG.password}))
+        .send()
+        .await;
+
+    let res = match res {
+        Ok(res) if res.status() == 200 => res,
+        Ok(res) => {
+            write_err_logs(None, Some(res.status().as_str()), "Logging in").await;
+            return None;
+        }
+        Err(_) => {
+            write_err_logs(None, None, "Logging in").await;
+            return None;
+        }
+    };
+
+    let token = res.json::<Value>().await.ok()?.get("token")?.as_str()?.to_string();
+    *TOKEN.lock().unwrap() = Some(token.clone());
+    Some(token)
+}
+
+/// The cached token, logging in first if there isn't one
+pub async fn token() -> Option<String> {
+    let cached = TOKEN.lock().unwrap().clone();
+    match cached {
+        Some(token) => Some(token),
+        None => login().await,
+    }
+}
+
+/// Drops the cached token so the next request logs in again
+pub fn forget_token() {
+    TOKEN.lock().unwrap().take();
+}
+
+async fn authorize(req: RequestBuilder) -> RequestBuilder {
+    match token().await {
+        Some(token) => req.bearer_auth(token),
+        None => req,
+    }
+}
+
+/// Sends a request to the sync server with the bearer token. A 401 means the token expired, so it
+/// is dropped and the request retried once with a fresh one, unless its body was a stream.
+pub async fn send(req: RequestBuilder) -> reqwest::Result<Response> {
+    let retry = req.try_clone();
+    let res = authorize(req).await.send().await?;
+
+    if res.status() != StatusCode::UNAUTHORIZED {
+        return Ok(res);
+    }
+
+    forget_token();
+    match retry {
+        Some(req) => authorize(req).await.send().await,
+        None => Ok(res),
+    }
+}
+
+/// Handshake request for the sync server's websocket, carrying the cached token
+pub fn ws_request() -> Request {
+    let mut request = server::ws_url().into_client_request().unwrap();
+
+    if let Some(token) = TOKEN.lock().unwrap().as_ref()
+        && let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", token))
+    {
+        request.headers_mut().insert(AUTHORIZATION, value);
+    }
+
+    request
+}
@@ -1,9 +1,12 @@
 use std::{
-    fs::{self, File},
+    fs::{self, File, OpenOptions},
     io::Write,
+    path::{Path, PathBuf},
 };
 
+use common::config::{config_dir, take_config_arg};
 use serde::Deserialize;
+use toml::Table;
 
 use crate::setup;
 
@@ -17,41 +20,194 @@ pub struct Config {
     pub log_path: String,
     pub changes_path: String,
     pub error_logs: String,
+    #[serde(default = "default_manifest_path")]
+    pub manifest_path: String,
+    /// Sync server with its scheme and port, e.g. `https://sync.example.com:3000`
+    #[serde(default = "default_server_url")]
+    pub server_url: String,
+    /// PEM bundle trusted on top of the usual roots, for a server behind a private CA
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    /// SHA-256 of the server's certificate in hex, colons allowed. Any other certificate is
+    /// refused, even a valid one.
+    #[serde(default)]
+    pub pinned_cert: Option<String>,
+    /// Localhost port of the control API, 0 picks a free one. Either way it's written to
+    /// `control.json` next to this file.
+    #[serde(default)]
+    pub control_port: u16,
+    /// Website backend that issues the tokens the sync server accepts
+    #[serde(default = "default_auth_url")]
+    pub auth_url: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    /// Kept out of sync on top of any `.dsyncignore` files, in the same syntax, e.g. `node_modules/`
+    #[serde(default)]
+    pub excluded_folders: Vec<String>,
+    /// Extra folder pairs, used instead of `storage_path` when set
+    #[serde(default)]
+    pub folders: Vec<FolderConfig>,
+    /// Transfers running at the same time
+    #[serde(default = "default_transfer_concurrency")]
+    pub transfer_concurrency: usize,
+    /// Failed attempts at sending a change before it's given up on and moved to the dead letter file
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Upload rate in KiB per second, 0 for no limit
+    #[serde(default)]
+    pub upload_limit: u64,
+    /// Download rate in KiB per second, 0 for no limit
+    #[serde(default)]
+    pub download_limit: u64,
+    /// Times of day with other limits, the first window containing the current time applies
+    #[serde(default)]
+    pub limit_schedule: Vec<LimitWindow>,
 }
 
-pub fn load_config() -> Config {
+/// `[[folders]]` entry mapping a local folder to a folder in the user's remote storage
+#[derive(Deserialize)]
+pub struct FolderConfig {
+    pub local: String,
+    /// `docs` syncs with `docs/` on the server, empty means the whole storage
+    #[serde(default)]
+    pub remote: String,
+}
 
-    let folder = format!("C:/Users/{}/AppData/Roaming/Drive_Sync", whoami::username());
-    let file_path = format!("{}/config.toml", folder);
-    let res = fs::read_to_string(file_path.clone());
-
-    if res.is_err() {
-
-        fs::create_dir_all(&folder).unwrap();
-        let file = File::create(file_path.clone());
-        let log_path = format!("{}/logs.toml", folder);
-        let changes_path = format!("{}/changes.toml", folder);
-        let _ = file.unwrap().write(
-            format!(
-                "storage_path=\"\"\nlog_path = \"{}\"\nchanges_path = \"{}\"",
-                log_path, changes_path
-            )
-            .as_bytes(),
-        );
-        let _ = File::create(log_path);
-        let _ = File::create(changes_path);
+/// `[[limit_schedule]]` entry, e.g. `from = "22:00"`, `to = "07:00"` and `upload_limit = 0` to
+/// upload without a limit at night
+#[derive(Deserialize)]
+pub struct LimitWindow {
+    /// Local time as `HH:MM`, a window ending before it starts runs past midnight
+    pub from: String,
+    pub to: String,
+    /// KiB per second like `upload_limit`, unset keeps the usual limit
+    #[serde(default)]
+    pub upload_limit: Option<u64>,
+    #[serde(default)]
+    pub download_limit: Option<u64>,
+}
+
+fn default_manifest_path() -> String {
+    "manifest.json".to_string()
+}
+
+fn default_server_url() -> String {
+    "http://localhost:3000".to_string()
+}
+
+fn default_transfer_concurrency() -> usize {
+    4
+}
+
+fn default_max_retries() -> u32 {
+    8
+}
+
+fn default_auth_url() -> String {
+    "http://localhost:5003".to_string()
+}
+
+/// Folder for the default logs, changes and manifest: `$XDG_STATE_HOME/drive_sync` on Linux,
+/// next to the config elsewhere
+pub fn state_dir() -> PathBuf {
+    match dirs::state_dir() {
+        Some(base) => base.join("drive_sync"),
+        None => config_dir(),
     }
+}
 
-    let res = fs::read_to_string("config.toml");
-    let config: Config = toml::from_str(&res.unwrap().clone()).unwrap();
+/// The config file in use, from `--config`, then `DRIVE_SYNC_CONFIG`, then the config folder
+pub fn config_path() -> PathBuf {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    common::config::config_path(take_config_arg(&mut args))
+}
+
+/// Run with `--headless`: no windows, just the watcher and sync loop in the foreground
+pub fn headless() -> bool {
+    std::env::args().skip(1).any(|arg| arg == "--headless")
+}
+
+/// Reads the config file as a table, writing the defaults first if there isn't one yet
+fn read_table(path: &Path) -> Table {
+    if !path.exists() {
+        let state = state_dir();
+        let _ = fs::create_dir_all(&state);
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+
+        let mut table = Table::new();
+        table.insert("storage_path".into(), "".into());
+        for (key, name) in [
+            ("log_path", "logs.jsonl"),
+            ("changes_path", "changes.jsonl"),
+            ("error_logs", "logs"),
+            ("manifest_path", "manifest.json"),
+        ] {
+            let file = state.join(name);
+            if name.ends_with(".jsonl") {
+                let _ = File::create(&file);
+            }
+            table.insert(key.into(), file.to_string_lossy().replace('\\', "/").into());
+        }
+        write_table(path, &table);
+        return table;
+    }
+
+    let content = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Couldn't read config {}: {}", path.display(), e));
+    toml::from_str(&content)
+        .unwrap_or_else(|e| panic!("Invalid config {}: {}", path.display(), e))
+}
+
+/// Written readable by its owner only, like `control.json`, since it holds the account password
+fn write_table(path: &Path, table: &Table) {
+    let mut options = OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let Ok(mut file) = options.open(path) else {
+        return;
+    };
+    // The mode only applies to new files, configs written before keep whatever they had
+    #[cfg(unix)]
+    let _ = file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600));
+    let _ = file.write_all(toml::to_string(table).unwrap_or_default().as_bytes());
+}
+
+/// Sets a few top level keys in the config file, leaving everything else in it as it was
+pub fn update_config(values: &[(&str, &str)]) {
+    let path = config_path();
+    let mut table = read_table(&path);
+    for (key, value) in values {
+        table.insert(key.to_string(), value.to_string().into());
+    }
+    write_table(&path, &table);
+}
+
+pub fn load_config() -> Config {
+    let path = config_path();
+    let config: Config = read_table(&path)
+        .try_into()
+        .unwrap_or_else(|e| panic!("Invalid config {}: {}", path.display(), e));
 
-    let path = config.storage_path.clone();
+    if config.storage_path.is_empty() && config.folders.is_empty() {
+        // Nothing to show the setup window on
+        if headless() {
+            eprintln!(
+                "No storage_path or [[folders]] in {}, set one up before running headless",
+                path.display()
+            );
+            std::process::exit(1);
+        }
 
-    if path == "" {
-        setup::run_setup();
-        let res = fs::read_to_string(file_path);
-        let config: Config = toml::from_str(&res.unwrap()).unwrap();
-        return config;
+        let _ = setup::run_setup();
+        return read_table(&path)
+            .try_into()
+            .unwrap_or_else(|e| panic!("Invalid config {}: {}", path.display(), e));
     }
 
     config
@@ -0,0 +1,130 @@
+use std::{path::Path, sync::Mutex};
+
+use chrono::Local;
+use common::{
+    Event, EventKind,
+    manifest::{FileEntry, file_entry},
+};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    connection::{UploadResult, get_file, send_file, write_err_logs},
+    echo,
+    folders::SyncFolder,
+    util::publish,
+};
+
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub path: String,
+    pub copy: String,
+    pub date: String,
+}
+
+/// Conflicts resolved since the user last dismissed them in the UI.
+pub static CONFLICTS: Lazy<Mutex<Vec<Conflict>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// A file edited here inside a folder deleted on the server, left where it is and sent again
+pub fn kept_after_delete(path: &str) {
+    println!("{} was deleted on the server but changed here, keeping it", path);
+    CONFLICTS.lock().unwrap().push(Conflict {
+        path: path.to_string(),
+        copy: path.to_string(),
+        date: Local::now().format("%Y-%m-%d").to_string(),
+    });
+}
+
+/// `dir/report.docx` -> `dir/report (conflicted copy from laptop 2026-01-31).docx`
+pub fn conflicted_copy_name(path: &str, host: &str, date: &str) -> String {
+    let file = Path::new(path);
+    let stem = file
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let name = match file.extension() {
+        Some(ext) => format!(
+            "{} (conflicted copy from {} {}).{}",
+            stem,
+            host,
+            date,
+            ext.to_string_lossy()
+        ),
+        None => format!("{} (conflicted copy from {} {})", stem, host, date),
+    };
+
+    match path.rsplit_once('/') {
+        Some((dir, _)) => format!("{}/{}", dir, name),
+        None => name,
+    }
+}
+
+/// Copies the local edits aside through the staging folder, so the watcher only sees the finished
+/// copy and doesn't send it again after it's uploaded
+async fn keep_copy(folder: &SyncFolder, original: &Path, copy: &Path) -> std::io::Result<()> {
+    let key = format!("{:x}", Sha256::digest(copy.to_string_lossy().as_bytes()));
+    let staged = folder.staging_path(&format!("{}.copy", key));
+    tokio::fs::create_dir_all(folder.staging_path("")).await?;
+    tokio::fs::copy(original, &staged).await?;
+
+    if let Some(entry) = file_entry(&staged) {
+        echo::expect_file(copy, entry);
+    }
+    let renamed = tokio::fs::rename(&staged, copy).await;
+    if renamed.is_err() {
+        let _ = tokio::fs::remove_file(&staged).await;
+    }
+    renamed
+}
+
+/// Keeps both versions of a file that changed locally and on the server since the last sync. The
+/// local edits are copied aside and uploaded as a conflicted copy, then the server version is
+/// downloaded over the original path. Returns the entries both sides now agree on.
+pub async fn resolve_conflict(folder: &SyncFolder, path: &str) -> Vec<(String, FileEntry)> {
+    let host = whoami::devicename();
+    let date = Local::now().format("%Y-%m-%d").to_string();
+
+    let mut copy = conflicted_copy_name(path, &host, &date);
+    let mut n = 2;
+    while tokio::fs::metadata(folder.local_path(&copy)).await.is_ok() {
+        copy = conflicted_copy_name(path, &host, &format!("{} {}", date, n));
+        n += 1;
+    }
+
+    let original = folder.local_path(path);
+    let copy_path = folder.local_path(&copy);
+    if let Err(e) = keep_copy(folder, &original, &copy_path).await {
+        write_err_logs(
+            Some(path),
+            Some(format!("Error keeping conflicted copy {}", e).as_str()),
+            "Resolving conflict",
+        )
+        .await;
+        return Vec::new();
+    }
+
+    println!("Conflict on {}, keeping local edits as {}", path, copy);
+    CONFLICTS.lock().unwrap().push(Conflict {
+        path: path.to_string(),
+        copy: copy.clone(),
+        date,
+    });
+
+    let mut synced = Vec::new();
+
+    if send_file(folder, copy.clone(), Some(String::new())).await == UploadResult::Done
+        && let Some(entry) = file_entry(&copy_path)
+    {
+        publish(folder, &[Event::new(EventKind::Create { path: copy.clone() })]).await;
+        synced.push((copy, entry));
+    }
+
+    if get_file(folder, path.to_string()).await
+        && let Some(entry) = file_entry(&original)
+    {
+        synced.push((path.to_string(), entry));
+    }
+
+    synced
+}
@@ -1,342 +1,658 @@
 use std::{
     io::ErrorKind,
     net::TcpStream,
-    sync::{Arc, Mutex, atomic::Ordering},
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
     thread::{self, sleep},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use reqwest::{Body, Client, get};
-use serde_json::{from_str, json};
-use tokio::fs;
-use tungstenite::{WebSocket, connect, stream::MaybeTlsStream};
+use common::{
+    ChangeFeed, Envelope, Event, EventKind, Notification,
+    manifest::{Manifest, file_entry},
+};
+use futures_util::StreamExt;
+use reqwest::Body;
+use serde_json::json;
+use tokio::{fs, runtime::Handle};
+use tungstenite::{
+    Connector, HandshakeError, WebSocket, client_tls_with_config, stream::MaybeTlsStream,
+};
 
 use crate::{
-    config::CONFIG,
-    util::{Events, IS_ANALYSING_LOGS, analyse_logs},
+    activity::{self, Retry},
+    auth::{self, ws_request},
+    conflict::{kept_after_delete, resolve_conflict},
+    delta::{DELTA_MIN_SIZE, send_file_delta},
+    echo,
+    folders::SyncFolder,
+    progress::{self, Direction},
+    server::{self, HTTP, TLS},
+    throttle,
+    transfer::{CHUNK_SIZE, download_file, send_file_chunked},
+    util::{DEVICE_ID, SYNC_NOW},
 };
 
-pub fn create_socket() -> Arc<Mutex<Option<WebSocket<MaybeTlsStream<TcpStream>>>>> {
-    let socket: Arc<Mutex<Option<WebSocket<MaybeTlsStream<TcpStream>>>>> =
-        Arc::new(Mutex::new(None));
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// The server pings every 30 seconds too, so this long without anything means the link is gone
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(90);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq)]
+pub enum UploadResult {
+    Done,
+    /// The server copy changed since the base version the upload was made against
+    Conflict,
+    Failed,
+}
 
-    {
-        let socket_clone = Arc::clone(&socket);
-        thread::spawn(move || {
-            loop {
-                let mut guard = socket_clone.lock().unwrap();
-                if let Some(sock) = guard.as_mut() {
-                    match sock.read() {
-                        Ok(msg) => match msg.to_text().unwrap() {
-                            "update" => {
-                                while IS_ANALYSING_LOGS.load(Ordering::Relaxed) {
-                                    tokio::spawn(analyse_logs());
-                                }
-                            }
-                            _ => (),
-                        },
-                        Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {}
-                        Err(_) => {}
-                    }
-                }
-                drop(guard);
-                sleep(Duration::from_millis(100));
-            }
-        });
+#[derive(Debug, PartialEq)]
+pub enum RenameResult {
+    Done,
+    /// The server never had the source, say when it was created and renamed between two syncs
+    Missing,
+    Failed,
+}
+
+pub enum ChangesResult {
+    Feed(ChangeFeed),
+    /// The server can't serve the cursor, only a full reconcile can catch up. Holds the cursor to
+    /// continue from afterwards.
+    Gone(u64),
+    Failed,
+}
+
+/// Websocket to the sync server, kept up by a background thread that reconnects with backoff
+#[derive(Default)]
+pub struct Socket {
+    connected: AtomicBool,
+    /// Switched off from the UI, the thread stays disconnected until it's switched back on
+    disabled: AtomicBool,
+}
+
+impl Socket {
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
     }
 
-    socket
+    pub fn is_enabled(&self) -> bool {
+        !self.disabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.disabled.store(!enabled, Ordering::Relaxed);
+    }
 }
 
-pub fn connect_to_ws(socket: Arc<Mutex<Option<WebSocket<MaybeTlsStream<TcpStream>>>>>) {
-    tokio::task::spawn_blocking(move || {
-        let mut guard = socket.lock().unwrap();
-        let ws = connect("ws://localhost:3000/ws");
-        if let Ok((mut ws, _)) = ws {
-            let ws_stream = ws.get_mut();
-            if let MaybeTlsStream::Plain(tcp) = ws_stream {
-                tcp.set_nonblocking(true).ok();
+/// Starts the thread keeping the websocket connected. Must be called inside the tokio runtime.
+pub fn create_socket() -> Arc<Socket> {
+    let socket = Arc::new(Socket::default());
+    let runtime = Handle::current();
+
+    let state = Arc::clone(&socket);
+    thread::spawn(move || {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            if !state.is_enabled() {
+                sleep(Duration::from_secs(1));
+                continue;
             }
-            *guard = Some(ws);
+
+            // Log in first so the handshake carries a token
+            runtime.block_on(auth::token());
+            let mut ws = match connect() {
+                Ok(ws) => ws,
+                Err(tungstenite::Error::Http(res)) if res.status() == 401 => {
+                    auth::forget_token();
+                    sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+                Err(_) => {
+                    sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            };
+            backoff = MIN_BACKOFF;
+
+            // A short read timeout lets the loop send heartbeats and notice being switched off
+            let tcp = match ws.get_ref() {
+                MaybeTlsStream::Plain(tcp) => Some(tcp),
+                MaybeTlsStream::Rustls(tls) => Some(tls.get_ref()),
+                _ => None,
+            };
+            if let Some(tcp) = tcp {
+                let _ = tcp.set_read_timeout(Some(Duration::from_secs(1)));
+            }
+            state.connected.store(true, Ordering::Relaxed);
+            // Whatever was pushed while disconnected was missed, so catch up straight away
+            SYNC_NOW.notify_one();
+
+            listen(&mut ws, &state);
+
+            state.connected.store(false, Ordering::Relaxed);
+            let _ = ws.close(None);
+            let _ = ws.flush();
         }
     });
+
+    socket
 }
 
-pub fn edit_server_side(event_type: String, path: String) {
-    match event_type.as_str() {
-        "Modify" => {
-            tokio::spawn(send_file(path));
+/// Opens the websocket, over TLS with the configured CA and pin when the server uses HTTPS
+fn connect() -> tungstenite::Result<WebSocket<MaybeTlsStream<TcpStream>>> {
+    let request = ws_request();
+    let uri = request.uri();
+    let host = uri.host().unwrap_or_default().to_string();
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("wss") { 443 } else { 80 });
+    let stream = TcpStream::connect((host.trim_matches(['[', ']']), port))?;
+
+    match client_tls_with_config(request, stream, None, Some(Connector::Rustls(TLS.clone()))) {
+        Ok((ws, _)) => Ok(ws),
+        Err(HandshakeError::Failure(e)) => Err(e),
+        // The stream blocks, so the handshake can't be interrupted
+        Err(HandshakeError::Interrupted(_)) => Err(tungstenite::Error::ConnectionClosed),
+    }
+}
+
+/// Reads notifications until the connection drops, goes quiet or is switched off
+fn listen(ws: &mut WebSocket<MaybeTlsStream<TcpStream>>, state: &Socket) {
+    let mut last_seen = Instant::now();
+    let mut last_ping = Instant::now();
+
+    while state.is_enabled() {
+        match ws.read() {
+            Ok(tungstenite::Message::Text(text)) => {
+                last_seen = Instant::now();
+                // Our own changes are already applied here
+                if let Ok(notification) = serde_json::from_str::<Notification>(&text)
+                    && notification
+                        .changes
+                        .iter()
+                        .any(|c| c.origin.as_deref() != Some(DEVICE_ID.as_str()))
+                {
+                    SYNC_NOW.notify_one();
+                }
+            }
+            Ok(tungstenite::Message::Close(_)) => return,
+            Ok(_) => last_seen = Instant::now(),
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(_) => return,
         }
-        "Remove" => {
-            tokio::spawn(send_delete_file(path));
+
+        if last_seen.elapsed() > HEARTBEAT_TIMEOUT {
+            return;
         }
-        "Rename" => {
-            tokio::spawn(send_rename_file(path));
+        if last_ping.elapsed() > HEARTBEAT_INTERVAL {
+            if ws.send(tungstenite::Message::Ping(Default::default())).is_err() {
+                return;
+            }
+            last_ping = Instant::now();
         }
-        _ => (),
     }
 }
 
-async fn send_file(path: String) {
-    println!("Path:{}", path);
-    let mut client_name = path.to_string();
+/// Applies a local change on the server, false if it has to be tried again. `base` is shared by
+/// the changes sent at the same time.
+pub async fn edit_server_side(folder: &SyncFolder, event: &Event, base: &Mutex<Manifest>) -> bool {
+    match &event.kind {
+        EventKind::Create { path } | EventKind::Modify { path } => {
+            // Gone again before it was sent counts as sent, the delete after it is what's left
+            let local = folder.local_path(path);
+            if local.is_dir() || !local.exists() {
+                return true;
+            }
 
-    if path.contains("$-$") {
-        let split: Vec<_> = path.split("$-$").collect();
-        client_name = split[1].to_string();
+            // The base tracks what the server has, so later pulls know this edit isn't local only
+            let base_hash = base
+                .lock()
+                .unwrap()
+                .files
+                .get(path)
+                .map(|e| e.hash.clone())
+                .unwrap_or_default();
+            match send_file(folder, path.clone(), Some(base_hash)).await {
+                UploadResult::Done => {
+                    if let Some(entry) = file_entry(&folder.local_path(path)) {
+                        base.lock().unwrap().files.insert(path.clone(), entry);
+                    }
+                    true
+                }
+                UploadResult::Conflict => {
+                    let resolved = resolve_conflict(folder, path).await;
+                    base.lock().unwrap().files.extend(resolved);
+                    true
+                }
+                UploadResult::Failed => false,
+            }
+        }
+        EventKind::Delete { path } => {
+            let deleted = send_delete_file(folder, path.clone()).await;
+            if deleted {
+                base.lock().unwrap().files.remove(path);
+            }
+            deleted
+        }
+        EventKind::Rename { from, to } => match send_rename_file(folder, from, to).await {
+            RenameResult::Done => {
+                base.lock().unwrap().rename(from, to);
+                true
+            }
+            // Nothing to move, what's at `to` is new to the server and goes up as such
+            RenameResult::Missing => {
+                let inside = format!("{}/", from);
+                base.lock()
+                    .unwrap()
+                    .files
+                    .retain(|p, _| p != from && !p.starts_with(&inside));
+                true
+            }
+            RenameResult::Failed => false,
+        },
+        EventKind::Mkdir { path } => send_create_folder(folder, path).await,
     }
+}
 
-    let file = tokio::fs::File::open(format!("{}/{}", &CONFIG.storage_path, client_name))
+/// Uploads a local file. `base_hash` is the version last synced from the server, an empty string
+/// meaning the file should not exist there yet, so the server can refuse to overwrite newer edits.
+pub async fn send_file(folder: &SyncFolder, path: String, base_hash: Option<String>) -> UploadResult {
+    println!("Path:{}", path);
+    let key = folder.remote_path(&path);
+    let size = tokio::fs::metadata(folder.local_path(&path))
         .await
-        .unwrap();
+        .map(|m| m.len())
+        .unwrap_or(0);
+
+    progress::start(&key, Direction::Upload, size);
+    let (result, failure) = activity::attempt(upload_file(folder, path.clone(), base_hash, size)).await;
+    progress::finish(&key, result == UploadResult::Done);
+
+    match result {
+        UploadResult::Done => activity::synced(folder, "Upload", &path),
+        UploadResult::Failed => {
+            let retry = activity::resend(EventKind::Modify { path: path.clone() });
+            activity::failed_in(folder, "Upload", &path, failure, retry);
+        }
+        UploadResult::Conflict => (),
+    }
+    result
+}
 
-    let res = Client::new()
-        .post(format!("http://localhost:3000/files/{}", path))
-        .body(file_to_body(file))
-        .send()
-        .await;
+async fn upload_file(
+    folder: &SyncFolder,
+    path: String,
+    base_hash: Option<String>,
+    size: u64,
+) -> UploadResult {
+    let local = folder.local_path(&path);
+
+    // A known base version means the server has the file, so only the changed blocks need sending
+    if size > DELTA_MIN_SIZE
+        && base_hash.as_ref().is_some_and(|hash| !hash.is_empty())
+        && let Some(result) = send_file_delta(folder, &path, &local, base_hash.clone()).await
+    {
+        return result;
+    }
 
-    if res.is_err() {
-        write_err_logs(
-            None,
-            Some(res.unwrap_err().status().unwrap().as_str()),
-            "Sending file",
-        )
-        .await;
-        return;
+    if size > CHUNK_SIZE {
+        return send_file_chunked(folder, &path, &local, base_hash).await;
     }
 
-    let status = res.unwrap().status();
+    let file = tokio::fs::File::open(&local).await;
+    let Ok(file) = file else {
+        write_err_logs(Some(&path), None, "Opening file").await;
+        return UploadResult::Failed;
+    };
+
+    let mut req = HTTP
+        .post(server::url(&format!("files/{}", folder.remote_path(&path))))
+        .body(file_to_body(file, folder.remote_path(&path)));
+    if let Some(base_hash) = base_hash {
+        req = req.header("X-Base-Hash", base_hash);
+    }
+    let res = auth::send(req).await;
+
+    // Refused connections and timeouts have no status to report
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            let status = e.status();
+            write_err_logs(None, status.as_ref().map(|s| s.as_str()), "Sending file").await;
+            return UploadResult::Failed;
+        }
+    };
+
+    let status = res.status();
+    if status == 409 {
+        return UploadResult::Conflict;
+    }
     if status != 200 {
         write_err_logs(None, Some(status.as_str()), "Sending file").await;
+        return UploadResult::Failed;
     }
+
+    UploadResult::Done
 }
 
-fn file_to_body(file: tokio::fs::File) -> Body {
+/// Streams the file as a request body under the upload limit, reporting each chunk sent as
+/// progress on `key`
+fn file_to_body(file: tokio::fs::File, key: String) -> Body {
     let stream = tokio_util::codec::FramedRead::new(file, tokio_util::codec::BytesCodec::new());
+    let stream = throttle::limit_upload(stream)
+        .inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                progress::advance(&key, chunk.len() as u64);
+            }
+        });
     let body = Body::wrap_stream(stream);
     body
 }
 
-async fn send_delete_file(path: String) {
-    let res = Client::new()
-        .delete(format!("http://localhost:3000/files/{}", path))
-        .send()
-        .await;
-
-    if res.is_err() {
-        write_err_logs(
-            Some(&path),
-            Some(res.unwrap_err().status().unwrap().as_str()),
-            "Sending delete",
-        )
-        .await;
-        return;
+pub async fn send_delete_file(folder: &SyncFolder, path: String) -> bool {
+    let (ok, failure) = activity::attempt(delete_remote(folder, &path)).await;
+    if ok {
+        activity::synced(folder, "Delete", &path);
+    } else {
+        let retry = activity::resend(EventKind::Delete { path: path.clone() });
+        activity::failed_in(folder, "Delete", &path, failure, retry);
     }
+    ok
+}
 
-    let status = res.unwrap().status();
-    if status != 200 {
+async fn delete_remote(folder: &SyncFolder, path: &str) -> bool {
+    let url = server::url(&format!("files/{}", folder.remote_path(path)));
+    let res = auth::send(HTTP.delete(url)).await;
+
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            let status = e.status();
+            write_err_logs(
+                Some(path),
+                status.as_ref().map(|s| s.as_str()),
+                "Sending delete",
+            )
+            .await;
+            return false;
+        }
+    };
+
+    // Already gone on the server counts as deleted
+    let status = res.status();
+    if status != 200 && status != 404 {
         write_err_logs(None, Some(status.as_str()), "Sending delete").await;
+        return false;
     }
-}
 
-async fn send_rename_file(path: String) {
-    let split: Vec<_> = path.split("$-$").collect();
-    let old = split[0].to_string();
-    let new = split[1].to_string();
-    let res = Client::new()
-        .put("http://localhost:3000/files")
-        .header("Content-Type", "application/json")
-        .body(json!({"from": old, "to": new}).to_string())
-        .send()
-        .await;
+    true
+}
 
-    if res.is_err() {
-        write_err_logs(
-            None,
-            Some(res.unwrap_err().status().unwrap().as_str()),
-            "Sending rename",
-        )
-        .await;
-        return;
+async fn send_rename_file(folder: &SyncFolder, from: &str, to: &str) -> RenameResult {
+    let (result, failure) = activity::attempt(rename_remote(folder, from, to)).await;
+    let path = format!("{} → {}", from, to);
+    match result {
+        RenameResult::Done => activity::synced(folder, "Rename", &path),
+        RenameResult::Missing => (),
+        RenameResult::Failed => {
+            let retry =
+                activity::resend(EventKind::Rename { from: from.to_string(), to: to.to_string() });
+            activity::failed_in(folder, "Rename", &path, failure, retry);
+        }
     }
+    result
+}
+
+async fn rename_remote(folder: &SyncFolder, from: &str, to: &str) -> RenameResult {
+    let (from, to) = (folder.remote_path(from), folder.remote_path(to));
+    let res = auth::send(
+        HTTP
+            .put(server::url("files"))
+            .header("Content-Type", "application/json")
+            .body(json!({"from": from, "to": to}).to_string()),
+    )
+    .await;
+
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            let status = e.status();
+            write_err_logs(None, status.as_ref().map(|s| s.as_str()), "Sending rename").await;
+            return RenameResult::Failed;
+        }
+    };
 
-    let status = res.unwrap().status();
+    let status = res.status();
+    if status == 404 {
+        return RenameResult::Missing;
+    }
     if status != 200 {
         write_err_logs(None, Some(status.as_str()), "Sending rename").await;
+        return RenameResult::Failed;
     }
+
+    RenameResult::Done
 }
 
-pub fn edit_client_side(event_type: String, path: String) {
-    match event_type.as_str() {
-        "Modify" => {
-            tokio::spawn(get_file(path));
-        }
+async fn send_create_folder(folder: &SyncFolder, path: &str) -> bool {
+    let (ok, failure) = activity::attempt(create_remote_folder(folder, path)).await;
+    if ok {
+        activity::synced(folder, "Create folder", path);
+    } else {
+        let retry = activity::resend(EventKind::Mkdir { path: path.to_string() });
+        activity::failed_in(folder, "Create folder", path, failure, retry);
+    }
+    ok
+}
 
-        "Rename" => {
-            tokio::spawn(rename_file(path));
-        }
+async fn create_remote_folder(folder: &SyncFolder, path: &str) -> bool {
+    let url = server::url(&format!("folders/{}", folder.remote_path(path)));
+    let res = auth::send(HTTP.post(url)).await;
 
-        "Remove" => {
-            tokio::spawn(delete_file(path));
+    match res {
+        Ok(res) if res.status() != 200 => {
+            write_err_logs(Some(path), Some(res.status().as_str()), "Creating folder").await;
+            false
+        }
+        Ok(_) => true,
+        Err(_) => {
+            write_err_logs(Some(path), None, "Creating folder").await;
+            false
         }
-
-        _ => (),
     }
 }
 
-async fn get_file(file_path: String) {
-    let mut file_path = file_path;
-    let mut server_name = file_path.to_string();
-    if file_path.contains("$-$") {
-        let parts: Vec<_> = file_path.split("$-$").collect();
-        let old_name = format!("{}/{}", &CONFIG.storage_path.as_str(), parts[0]);
-        let new_name = format!("{}/{}", &CONFIG.storage_path.as_str(), parts[1]);
-        server_name = new_name.to_string();
-        if tokio::fs::metadata(&new_name).await.is_ok() {
-            file_path = parts[1].to_string();
-        } else if tokio::fs::metadata(&old_name).await.is_ok() {
-            file_path = parts[0].to_string();
-        } else {
-            file_path = parts[1].to_string();
-        }
-    }
+pub async fn get_file(folder: &SyncFolder, file_path: String) -> bool {
+    let key = folder.remote_path(&file_path);
+    progress::start(&key, Direction::Download, 0);
+    let (ok, failure) =
+        activity::attempt(download_file(folder, &file_path, &folder.local_path(&file_path))).await;
+    progress::finish(&key, ok);
 
-    let req = get(format!("http://localhost:3000/files/{}", server_name))
-        .await
-        .unwrap();
-
-    println!("Req status:{}", req.status());
-    let body = req.bytes().await.unwrap();
-
-    let path = format!("{}/{}", &CONFIG.storage_path, file_path);
-    let dir = std::path::Path::new(&path).parent().unwrap();
-
-    let create = tokio::fs::create_dir_all(dir).await;
-    if create.is_err() {
-        write_err_logs(
-            Some(&file_path),
-            Some(format!("Error creating dir{}", create.unwrap_err()).as_str()),
-            "Getting file",
-        )
-        .await;
-        return;
+    if ok {
+        activity::synced(folder, "Download", &file_path);
+    } else {
+        activity::failed_in(folder, "Download", &file_path, failure, Retry::Reconcile);
     }
-    let write = tokio::fs::write(&path, &body).await;
-    if write.is_err() {
-        write_err_logs(
-            Some(&path),
-            Some(format!("Error writing to file{}", write.unwrap_err()).as_str()),
-            "Getting file",
-        ).await;
+    ok
+}
+
+/// Removes a local file or folder the server no longer has. In a folder only what still matches
+/// `base` goes, along with the folders that leaves empty, edits made here are kept.
+pub async fn delete_file(folder: &SyncFolder, path: String, base: &Manifest) -> bool {
+    let (ok, failure) = activity::attempt(delete_local(folder, &path, base)).await;
+    if ok {
+        activity::synced(folder, "Delete locally", &path);
+    } else {
+        activity::failed_in(folder, "Delete locally", &path, failure, Retry::Reconcile);
     }
+    ok
 }
 
-async fn delete_file(path: String) {
-    let path = format!("{}{}", &CONFIG.storage_path, path);
+async fn delete_local(folder: &SyncFolder, relative: &str, base: &Manifest) -> bool {
+    let path = folder.local_path(relative).display().to_string();
     let metadata = tokio::fs::metadata(&path).await;
     if metadata.is_err() {
-        return;
+        return true;
     }
     let metadata = metadata.unwrap();
     if metadata.is_dir() {
-        let res = fs::remove_dir(&path).await;
-        if res.is_err() {
-            write_err_logs(Some(&path), None, "Deleting folder").await;
+        return delete_local_folder(folder, relative, base).await;
+    }
+
+    echo::expect_gone(Path::new(&path));
+    let res = fs::remove_file(&path).await;
+    if res.is_err() {
+        write_err_logs(Some(&path), None, "Deleting file").await;
+        return false;
+    }
+
+    true
+}
+
+/// Empties a folder deleted on the server of the files synced into it, then removes the folders
+/// left empty. Files changed since they were synced are kept and reported as conflicts, ignored
+/// ones are just left alone.
+async fn delete_local_folder(folder: &SyncFolder, relative: &str, base: &Manifest) -> bool {
+    let mut files = Vec::new();
+    let mut dirs = vec![relative.to_string()];
+    let mut i = 0;
+    while i < dirs.len() {
+        let mut entries = match fs::read_dir(folder.local_path(&dirs[i])).await {
+            Ok(entries) => entries,
+            Err(_) => {
+                write_err_logs(Some(&dirs[i]), None, "Reading folder").await;
+                return false;
+            }
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let child = format!("{}/{}", dirs[i], entry.file_name().to_string_lossy());
+            if entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+                dirs.push(child);
+            } else {
+                files.push(child);
+            }
         }
-    } else {
-        let res = fs::remove_file(&path).await;
-        if res.is_err() {
-            write_err_logs(Some(&path), None, "Deleting file").await;
+        i += 1;
+    }
+
+    let mut ok = true;
+    for file in files {
+        let local = folder.local_path(&file);
+        let synced = base.files.get(&file).is_some_and(|entry| {
+            file_entry(&local).is_some_and(|current| current.hash == entry.hash)
+        });
+        if !synced {
+            if !folder.is_ignored(&file) {
+                kept_after_delete(&file);
+            }
+            continue;
+        }
+
+        echo::expect_gone(&local);
+        if fs::remove_file(&local).await.is_err() {
+            write_err_logs(Some(&file), None, "Deleting file").await;
+            ok = false;
         }
     }
-}
 
-async fn rename_file(path: String) {
-    let split: Vec<_> = path.split("$-$").collect();
-    let old = split[0].to_string();
-    let new = split[1].to_string();
-    let old = format!("{}{}", &CONFIG.storage_path, old);
-    let new = format!("{}{}", &CONFIG.storage_path, new);
-    let res = fs::rename(&old, &new).await;
-    if res.is_err() {
-        write_err_logs(Some(format!("{},{}", new, old).as_str()), None, "Renaming").await;
+    // Deepest first, a folder still holding kept files stays
+    for dir in dirs.iter().rev() {
+        let local = folder.local_path(dir);
+        echo::expect_gone(&local);
+        let _ = fs::remove_dir(&local).await;
     }
+    ok
 }
 
-pub async fn fetch_logs() -> Option<Events> {
-    let res = get("http://localhost:3000/logs").await;
+/// The server's manifest for the pair's remote folder, with paths relative to it
+pub async fn fetch_manifest(folder: &SyncFolder) -> Option<Manifest> {
+    let res = auth::send(HTTP.get(server::url("manifest"))).await;
 
     if res.is_err() {
-        write_err_logs(
-            None,
-            Some(res.unwrap_err().status().unwrap().as_str()),
-            "Sending logs",
-        )
-        .await;
+        write_err_logs(None, None, "Fetching manifest").await;
         return None;
     }
-    let unwrapped = res.unwrap();
 
-    let status = unwrapped.status();
-    if status != 200 || status != 204 {
-        write_err_logs(None, Some(status.as_str()), "Sending logs").await;
+    let res = res.unwrap();
+    if res.status() != 200 {
+        write_err_logs(None, Some(res.status().as_str()), "Fetching manifest").await;
         return None;
     }
 
-    if status == 204 {
-        None
-    } else {
-        let res = unwrapped;
-        let body = res.text().await.unwrap();
-        from_str(&body).unwrap()
+    let remote: Manifest = res.json().await.ok()?;
+    let files = remote
+        .files
+        .into_iter()
+        .filter_map(|(path, entry)| Some((folder.from_remote_path(&path)?, entry)))
+        .collect();
+    Some(Manifest { files })
+}
+
+/// Changes the server accepted after `since`, from this and every other device
+pub async fn fetch_changes(since: u64) -> ChangesResult {
+    let res = auth::send(
+        HTTP
+            .get(server::url("changes"))
+            .query(&[("since", since)]),
+    )
+    .await;
+
+    let Ok(res) = res else {
+        write_err_logs(None, None, "Fetching changes").await;
+        return ChangesResult::Failed;
+    };
+
+    match res.status().as_u16() {
+        200 => match res.json::<ChangeFeed>().await {
+            Ok(feed) => ChangesResult::Feed(feed),
+            Err(_) => ChangesResult::Failed,
+        },
+        410 => match res.json::<ChangeFeed>().await {
+            Ok(feed) => ChangesResult::Gone(feed.cursor),
+            Err(_) => ChangesResult::Failed,
+        },
+        status => {
+            write_err_logs(None, Some(&status.to_string()), "Fetching changes").await;
+            ChangesResult::Failed
+        }
     }
 }
 
-pub async fn send_logs(events: Events) {
+pub async fn send_logs(events: Vec<Event>) {
     println!("Sending");
 
-    let client = Client::new();
-    let res = client
-        .post("http://localhost:3000/logs")
-        .json(&events)
-        .send()
-        .await;
+    let res = auth::send(
+        HTTP
+            .post(server::url("logs"))
+            .header("X-Device-Id", DEVICE_ID.as_str())
+            .json(&Envelope::new(events)),
+    )
+    .await;
 
-    if res.is_err() {
-        write_err_logs(
-            None,
-            Some(res.unwrap_err().status().unwrap().as_str()),
-            "Sending logs",
-        )
-        .await;
-        return;
-    }
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            let status = e.status();
+            write_err_logs(None, status.as_ref().map(|s| s.as_str()), "Sending logs").await;
+            return;
+        }
+    };
 
-    let status = res.unwrap().status();
+    let status = res.status();
     if status != 200 {
         write_err_logs(None, Some(status.as_str()), "Sending logs").await;
     }
 }
 
-async fn write_err_logs(path: Option<&str>, response: Option<&str>, event: &str) {
-    let response = if response.is_some() {
-        let response = response.unwrap();
-        format!("|response:{}", response)
-    } else {
-        "".to_string()
-    };
-
-    let path = if path.is_some() {
-        let path = path.unwrap();
-        format!("|path:{}", path)
-    } else {
-        "".to_string()
-    };
-
-    let _ = fs::write(
-        &CONFIG.error_logs,
-        format!("event:{}{}{}", event, response, path),
-    )
-    .await;
+/// Logs a failed request or file operation to the activity log
+pub async fn write_err_logs(path: Option<&str>, response: Option<&str>, event: &str) {
+    activity::failed(event, path, response);
 }
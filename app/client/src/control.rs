@@ -0,0 +1,265 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::Ordering},
+};
+
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode, header::AUTHORIZATION},
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, post},
+};
+use common::{
+    Event, EventKind,
+    manifest::{Manifest, file_entry},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+
+use crate::{
+    activity,
+    config::{self, CONFIG},
+    connection::Socket,
+    folders::{FOLDERS, SyncFolder, folder_for},
+    queue,
+    util::{IS_ANALYSING_LOGS, PAUSED, SYNC_NOW, get_events, set_paused},
+};
+
+/// Port and token of the running client, next to its config and only readable by its user
+#[derive(Serialize, Deserialize)]
+pub struct ControlFile {
+    pub port: u16,
+    pub token: String,
+}
+
+pub fn control_file_path() -> PathBuf {
+    common::config::control_file_path(&config::config_path())
+}
+
+/// Serves the control API on localhost for the UI-less client and the `dsync` CLI. Every request
+/// needs the token from the control file as a bearer token.
+pub async fn serve(socket: Arc<Socket>) {
+    let listener = match TcpListener::bind(("127.0.0.1", CONFIG.control_port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Control API unavailable: {}", e);
+            return;
+        }
+    };
+    let Ok(address) = listener.local_addr() else {
+        return;
+    };
+
+    let token: String = rand::random::<[u8; 16]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let control = ControlFile {
+        port: address.port(),
+        token: token.clone(),
+    };
+    if let Err(e) = write_control_file(&control_file_path(), &control) {
+        eprintln!("Couldn't write the control file: {}", e);
+        return;
+    }
+    println!("Control API on {}", address);
+
+    let app = Router::new()
+        .route("/status", get(status))
+        .route("/queue", get(queue))
+        .route("/errors", get(errors))
+        .route("/paths", post(path_states))
+        .route("/pause", post(pause))
+        .route("/resume", post(resume))
+        .route("/sync", post(sync_now))
+        .route("/retry", post(retry))
+        .layer(middleware::from_fn(move |req, next| {
+            check_token(token.clone(), req, next)
+        }))
+        .with_state(socket);
+
+    let _ = axum::serve(listener, app).await;
+}
+
+fn write_control_file(path: &Path, control: &ControlFile) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = options.open(path)?;
+    file.write_all(serde_json::to_string(control)?.as_bytes())
+}
+
+async fn check_token(
+    token: String,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let given = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    if given != Some(token.as_str()) {
+        return Err((StatusCode::UNAUTHORIZED, "Missing or wrong control token"));
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Local changes not sent to the server yet, from the journal and those held during a cycle
+async fn pending_events(folder: &SyncFolder) -> Vec<Event> {
+    let mut events = get_events(&folder.changes_path);
+    events.extend(folder.pending.lock().await.iter().cloned());
+    events
+}
+
+async fn status(State(socket): State<Arc<Socket>>) -> Json<Value> {
+    let mut folders = Vec::new();
+    for folder in FOLDERS.iter() {
+        let status = folder.status.lock().unwrap().clone();
+        folders.push(json!({
+            "local": folder.local,
+            "remote": folder.remote,
+            "status": status,
+            "pending": pending_events(folder).await.len(),
+            "retrying": queue::retries(folder).len(),
+            "dead_letters": queue::dead_letters(folder).len(),
+        }));
+    }
+
+    Json(json!({
+        "connected": socket.is_connected(),
+        "paused": PAUSED.load(Ordering::Relaxed),
+        "syncing": IS_ANALYSING_LOGS.load(Ordering::Relaxed),
+        "folders": folders,
+        "errors": activity::failures().len(),
+    }))
+}
+
+async fn queue() -> Json<Value> {
+    let mut folders = Vec::new();
+    for folder in FOLDERS.iter() {
+        folders.push(json!({
+            "local": folder.local,
+            "remote": folder.remote,
+            "events": pending_events(folder).await,
+            "retrying": queue::retries(folder),
+            "dead_letters": queue::dead_letters(folder),
+        }));
+    }
+
+    Json(json!({ "folders": folders }))
+}
+
+async fn errors() -> Json<Value> {
+    Json(json!({ "errors": activity::failures() }))
+}
+
+#[derive(Deserialize)]
+struct RetryRequest {
+    id: u64,
+}
+
+/// Retries a failed entry from `/errors` on the next sync cycle
+async fn retry(Json(req): Json<RetryRequest>) -> StatusCode {
+    if activity::retry(req.id) {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[derive(Deserialize)]
+struct PathsRequest {
+    /// Absolute local paths
+    paths: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PathState {
+    path: String,
+    /// Local folder of the pair the path is in, `None` when it isn't synced at all
+    folder: Option<PathBuf>,
+    remote: Option<String>,
+    state: &'static str,
+}
+
+/// Where each path is at: `outside` any pair, `ignored`, `pending` upload or retry, `synced`, `modified`
+/// since the last sync, `deleted` locally, `folder` or `missing`
+async fn path_states(Json(req): Json<PathsRequest>) -> Json<Vec<PathState>> {
+    let mut states = Vec::new();
+    for path in req.paths {
+        let local = PathBuf::from(&path);
+        let Some(folder) = folder_for(&local) else {
+            states.push(PathState {
+                path,
+                folder: None,
+                remote: None,
+                state: "outside",
+            });
+            continue;
+        };
+
+        let relative = folder.relative_path(&local);
+        let prefix = format!("{}/", relative);
+        let touches = |p: &str| p == relative || p.starts_with(&prefix);
+        let mut waiting = pending_events(folder).await;
+        waiting.extend(queue::retries(folder).into_iter().map(|retry| retry.event));
+        let pending = waiting.iter().any(|event| match &event.kind {
+            EventKind::Rename { from, to } => touches(from) || touches(to),
+            _ => touches(event.path()),
+        });
+        let synced = Manifest::load(&folder.manifest_path).files.get(&relative).cloned();
+
+        let state = if folder.is_ignored(&relative) {
+            "ignored"
+        } else if pending {
+            "pending"
+        } else if local.is_dir() {
+            "folder"
+        } else {
+            match (file_entry(&local), synced) {
+                (Some(entry), Some(synced)) if entry.hash == synced.hash => "synced",
+                (Some(_), _) => "modified",
+                (None, Some(_)) => "deleted",
+                (None, None) => "missing",
+            }
+        };
+
+        states.push(PathState {
+            path,
+            folder: Some(folder.local.clone()),
+            remote: Some(folder.remote_path(&relative)),
+            state,
+        });
+    }
+
+    Json(states)
+}
+
+async fn pause() -> StatusCode {
+    set_paused(true);
+    StatusCode::OK
+}
+
+async fn resume() -> StatusCode {
+    set_paused(false);
+    StatusCode::OK
+}
+
+async fn sync_now() -> StatusCode {
+    SYNC_NOW.notify_one();
+    StatusCode::OK
+}
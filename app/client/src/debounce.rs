@@ -0,0 +1,296 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use common::{Event, EventKind, manifest::stat_entry};
+
+use crate::{folders::SyncFolder, queue::overlaps};
+
+/// How long a path has to go without events before its change is journaled
+pub const QUIET_WINDOW: Duration = Duration::from_secs(1);
+
+/// A change waiting for its path to go quiet
+struct Waiting {
+    folder: &'static SyncFolder,
+    event: Event,
+    /// When the latest event for the path came in
+    last: Instant,
+    /// Size and mtime of the file when last looked at, it's journaled once they stop changing
+    seen: Option<(u64, i64)>,
+}
+
+/// Changes from the watcher in the order they came in, one per path until it's journaled
+struct Debouncer {
+    waiting: Vec<Waiting>,
+}
+
+static DEBOUNCER: Mutex<Debouncer> = Mutex::new(Debouncer {
+    waiting: Vec::new(),
+});
+
+/// Whether anything is waiting for its path to go quiet
+pub fn waiting() -> bool {
+    !DEBOUNCER.lock().unwrap().waiting.is_empty()
+}
+
+/// Holds a change back until its path has been quiet for [`QUIET_WINDOW`], see [`Debouncer::push`]
+pub fn push(folder: &'static SyncFolder, event: Event) {
+    DEBOUNCER.lock().unwrap().push(folder, event, Instant::now());
+}
+
+/// Takes the changes that are ready to be journaled, see [`Debouncer::ready`]
+pub fn ready() -> Vec<(&'static SyncFolder, Event)> {
+    DEBOUNCER.lock().unwrap().ready(Instant::now())
+}
+
+/// Takes everything still waiting, for when the client is shutting down
+pub fn drain() -> Vec<(&'static SyncFolder, Event)> {
+    DEBOUNCER
+        .lock()
+        .unwrap()
+        .waiting
+        .drain(..)
+        .map(|entry| (entry.folder, entry.event))
+        .collect()
+}
+
+/// What two changes to the same path add up to, `None` if they cancel out
+fn merge(earlier: &EventKind, later: EventKind) -> Option<EventKind> {
+    use EventKind::*;
+
+    match (earlier, later) {
+        // Created and deleted again before it was sent, a temp file the server never needs
+        (Create { .. } | Mkdir { .. }, Delete { .. }) => None,
+        (Create { .. }, Modify { path }) => Some(Create { path }),
+        // The server still has the old file, so it's replaced rather than created
+        (Delete { .. }, Create { path }) => Some(Modify { path }),
+        (_, later) => Some(later),
+    }
+}
+
+/// Size and mtime of the file a create or modify is for, `None` for other changes or once it's gone
+fn snapshot(folder: &SyncFolder, event: &Event) -> Option<(u64, i64)> {
+    let (EventKind::Create { path } | EventKind::Modify { path }) = &event.kind else {
+        return None;
+    };
+    stat_entry(&folder.local_path(path), String::new()).map(|entry| (entry.size, entry.mtime))
+}
+
+impl Debouncer {
+    /// Holds a change back until its path has been quiet for [`QUIET_WINDOW`], merging it with the
+    /// change already waiting for the path. Renames aren't merged, and nothing is merged across one.
+    fn push(&mut self, folder: &'static SyncFolder, event: Event, now: Instant) {
+        let waiting = &mut self.waiting;
+
+        let mergeable = !matches!(event.kind, EventKind::Rename { .. });
+        let latest = waiting
+            .iter()
+            .rposition(|w| std::ptr::eq(w.folder, folder) && overlaps(&w.event, &event));
+        if mergeable
+            && let Some(i) = latest
+            && waiting[i].event.path() == event.path()
+            && !matches!(waiting[i].event.kind, EventKind::Rename { .. })
+        {
+            match merge(&waiting[i].event.kind, event.kind.clone()) {
+                Some(kind) => {
+                    let entry = &mut waiting[i];
+                    entry.event = Event { kind, ..event };
+                    entry.last = now;
+                }
+                None => {
+                    waiting.remove(i);
+                }
+            }
+            return;
+        }
+
+        waiting.push(Waiting {
+            folder,
+            event,
+            last: now,
+            seen: None,
+        });
+    }
+
+    /// Takes the changes whose path has gone quiet and whose file stopped growing since it was last
+    /// looked at. A change never goes ahead of an earlier one it overlaps that still has to wait.
+    fn ready(&mut self, now: Instant) -> Vec<(&'static SyncFolder, Event)> {
+        let waiting = &mut self.waiting;
+        let mut held: Vec<usize> = Vec::new();
+        let mut taken = Vec::new();
+
+        for i in 0..waiting.len() {
+            let entry = &mut waiting[i];
+            let quiet = now.saturating_duration_since(entry.last) >= QUIET_WINDOW;
+            let stable = quiet && {
+                let current = snapshot(entry.folder, &entry.event);
+                let stable = current.is_none() || current == entry.seen;
+                entry.seen = current;
+                stable
+            };
+
+            let blocked = held.iter().any(|&h| {
+                std::ptr::eq(waiting[h].folder, waiting[i].folder)
+                    && overlaps(&waiting[h].event, &waiting[i].event)
+            });
+            if stable && !blocked {
+                taken.push(i);
+            } else {
+                held.push(i);
+            }
+        }
+
+        let mut index = 0;
+        let mut ready = Vec::new();
+        waiting.retain(|entry| {
+            let keep = !taken.contains(&index);
+            if !keep {
+                ready.push((entry.folder, entry.event.clone()));
+            }
+            index += 1;
+            keep
+        });
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::folders::test_folders;
+
+    /// A pair with nothing on disk, so files it doesn't have count as already settled
+    fn folder(name: &str) -> &'static SyncFolder {
+        &test_folders(&format!("debounce-{}", name), &["sync"])[0]
+    }
+
+    fn event(kind: EventKind) -> Event {
+        Event::new(kind)
+    }
+
+    fn create(path: &str) -> Event {
+        event(EventKind::Create { path: path.to_string() })
+    }
+
+    fn modify(path: &str) -> Event {
+        event(EventKind::Modify { path: path.to_string() })
+    }
+
+    fn delete(path: &str) -> Event {
+        event(EventKind::Delete { path: path.to_string() })
+    }
+
+    fn kinds(ready: Vec<(&'static SyncFolder, Event)>) -> Vec<EventKind> {
+        ready.into_iter().map(|(_, event)| event.kind).collect()
+    }
+
+    fn debouncer() -> Debouncer {
+        Debouncer {
+            waiting: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn create_then_delete_cancels_out() {
+        let folder = folder("cancel");
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+        debouncer.push(folder, create("tmp.swx"), start);
+        debouncer.push(folder, delete("tmp.swx"), start);
+
+        assert!(debouncer.waiting.is_empty());
+        assert!(debouncer.ready(start + QUIET_WINDOW).is_empty());
+    }
+
+    #[test]
+    fn delete_then_create_then_delete_stays_a_delete() {
+        let folder = folder("recreate");
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+        debouncer.push(folder, delete("a.txt"), start);
+        debouncer.push(folder, create("a.txt"), start);
+        debouncer.push(folder, delete("a.txt"), start);
+
+        assert_eq!(
+            kinds(debouncer.ready(start + QUIET_WINDOW)),
+            [delete("a.txt").kind]
+        );
+    }
+
+    #[test]
+    fn create_then_modify_stays_a_create() {
+        let folder = folder("create");
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+        debouncer.push(folder, create("a.txt"), start);
+        debouncer.push(folder, modify("a.txt"), start);
+        debouncer.push(folder, modify("a.txt"), start);
+
+        assert_eq!(
+            kinds(debouncer.ready(start + QUIET_WINDOW)),
+            [create("a.txt").kind]
+        );
+    }
+
+    #[test]
+    fn waits_until_the_path_goes_quiet() {
+        let folder = folder("quiet");
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+        debouncer.push(folder, modify("a.txt"), start);
+        debouncer.push(folder, modify("a.txt"), start + QUIET_WINDOW / 2);
+
+        assert!(debouncer.ready(start + QUIET_WINDOW).is_empty());
+        assert_eq!(
+            kinds(debouncer.ready(start + QUIET_WINDOW * 2)),
+            [modify("a.txt").kind]
+        );
+    }
+
+    #[test]
+    fn later_change_never_overtakes_a_held_earlier_one() {
+        let folder = folder("order");
+        fs::create_dir_all(&folder.local).unwrap();
+        fs::write(folder.local_path("a.txt"), "still being written").unwrap();
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+        debouncer.push(folder, modify("a.txt"), start);
+        let rename = event(EventKind::Rename {
+            from: "a.txt".to_string(),
+            to: "b.txt".to_string(),
+        });
+        debouncer.push(folder, rename.clone(), start);
+        debouncer.push(folder, modify("other.txt"), start);
+
+        // The file is only seen for the first time, so its change and the rename after it wait
+        let later = start + QUIET_WINDOW;
+        assert_eq!(kinds(debouncer.ready(later)), [modify("other.txt").kind]);
+        assert_eq!(
+            kinds(debouncer.ready(later)),
+            [modify("a.txt").kind, rename.kind]
+        );
+        let _ = fs::remove_dir_all(folder.local.parent().unwrap());
+    }
+
+    #[test]
+    fn renames_are_not_merged_across() {
+        let folder = folder("rename");
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+        debouncer.push(folder, create("a.txt"), start);
+        let rename = event(EventKind::Rename {
+            from: "a.txt".to_string(),
+            to: "b.txt".to_string(),
+        });
+        debouncer.push(folder, rename.clone(), start);
+        debouncer.push(folder, delete("a.txt"), start);
+
+        assert_eq!(
+            kinds(debouncer.ready(start + QUIET_WINDOW)),
+            [create("a.txt").kind, rename.kind, delete("a.txt").kind]
+        );
+    }
+}
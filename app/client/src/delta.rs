@@ -0,0 +1,88 @@
+use std::{io, path::Path};
+
+use common::{
+    delta::{Signature, compute_delta},
+    manifest::hash_file,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    auth,
+    connection::UploadResult,
+    folders::SyncFolder,
+    server::{self, HTTP},
+    throttle,
+};
+
+/// Modified files smaller than this are cheaper to send whole
+pub const DELTA_MIN_SIZE: u64 = 1024 * 1024;
+/// Must stay under the server's body limit on `/delta`
+const DELTA_MAX_SIZE: u64 = 48 * 1024 * 1024;
+
+/// Sends only the changed blocks of `local` when the server already has a version of `path`.
+/// `None` means a delta isn't possible or worth it and the caller should upload the whole file.
+pub async fn send_file_delta(
+    folder: &SyncFolder,
+    path: &str,
+    local: &Path,
+    base_hash: Option<String>,
+) -> Option<UploadResult> {
+    let client = HTTP.clone();
+    let remote = folder.remote_path(path);
+    let res = auth::send(client.get(server::url(&format!("signatures/{}", remote))))
+        .await
+        .ok()?;
+    if res.status() != 200 {
+        return None;
+    }
+    let signature: Signature = res.json().await.ok()?;
+
+    let key = format!("{:x}", Sha256::digest(path.as_bytes()));
+    let delta_path = folder.staging_path(&format!("{}.delta", key));
+    let _ = tokio::fs::create_dir_all(folder.staging_path("")).await;
+
+    let local_path = local.to_path_buf();
+    let out = delta_path.clone();
+    let computed = tokio::task::spawn_blocking(move || {
+        let size = compute_delta(&local_path, &signature, &out)?;
+        Ok::<_, io::Error>((size, hash_file(&local_path)?, signature))
+    })
+    .await
+    .ok()?;
+
+    let Ok((size, hash, signature)) = computed else {
+        let _ = tokio::fs::remove_file(&delta_path).await;
+        return None;
+    };
+
+    let local_size = tokio::fs::metadata(local).await.ok()?.len();
+    if size > DELTA_MAX_SIZE || size >= local_size / 2 {
+        let _ = tokio::fs::remove_file(&delta_path).await;
+        return None;
+    }
+
+    let body = tokio::fs::read(&delta_path).await;
+    let _ = tokio::fs::remove_file(&delta_path).await;
+
+    let mut req = client
+        .post(server::url(&format!("delta/{}", remote)))
+        .header("X-Delta-Basis", signature.hash)
+        .header("X-Content-Hash", hash)
+        .body(throttle::limited_body(body.ok()?));
+    if let Some(base_hash) = base_hash {
+        req = req.header("X-Base-Hash", base_hash);
+    }
+
+    // Anything else, like the server copy changing under us, falls back to a full upload
+    match auth::send(req).await.ok()?.status().as_u16() {
+        200 => {
+            println!(
+                "Sent {} as a {} byte delta instead of {} bytes",
+                path, size, local_size
+            );
+            Some(UploadResult::Done)
+        }
+        409 => Some(UploadResult::Conflict),
+        _ => None,
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use common::{
+    Event, EventKind,
+    manifest::{FileEntry, stat_entry},
+};
+use once_cell::sync::Lazy;
+
+use crate::folders::SyncFolder;
+
+/// How long the watcher events for a change the sync engine made can take to come in
+const ECHO_WINDOW: Duration = Duration::from_secs(10);
+
+/// What a path is left as by something the engine did to it
+#[derive(Clone, Debug)]
+enum Expected {
+    /// A file with this content, written or moved there
+    File(FileEntry),
+    /// A folder, created or moved there
+    Folder,
+    /// Nothing, deleted or moved away
+    Gone,
+}
+
+/// Paths the sync engine changed itself, by full path. Registered before the change is made, the
+/// watcher can report it before the call making it returns.
+static EXPECTED: Lazy<Mutex<HashMap<PathBuf, (Expected, Instant)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn expect(path: &Path, expected: Expected) {
+    let mut pending = EXPECTED.lock().unwrap();
+    pending.retain(|_, (_, at)| at.elapsed() < ECHO_WINDOW);
+    pending.insert(path.to_path_buf(), (expected, Instant::now()));
+}
+
+/// A file about to be put at `path`, as `entry`. Renames keep the size, mtime and inode compared,
+/// so the entry can be taken from the file before it's moved.
+pub fn expect_file(path: &Path, entry: FileEntry) {
+    expect(path, Expected::File(entry));
+}
+
+/// A folder about to be created or moved to `path`, along with the missing folders above it
+pub fn expect_folder(path: &Path) {
+    for dir in path.ancestors().take_while(|dir| !dir.exists()) {
+        expect(dir, Expected::Folder);
+    }
+}
+
+/// A file or empty folder about to be deleted or moved away from `path`
+pub fn expect_gone(path: &Path) {
+    expect(path, Expected::Gone);
+}
+
+/// Whether `path` is still the way the engine left it
+fn still(expected: &Expected, path: &Path) -> bool {
+    match expected {
+        Expected::File(entry) => stat_entry(path, String::new()).is_some_and(|now| {
+            now.size == entry.size && now.mtime == entry.mtime && now.inode == entry.inode
+        }),
+        Expected::Folder => path.is_dir(),
+        Expected::Gone => !path.exists(),
+    }
+}
+
+/// Whether the event only reports changes the engine made, with every path it touches still the
+/// way the engine left it. Once a path has changed since, its events are real again.
+pub fn is_echo(folder: &SyncFolder, event: &Event) -> bool {
+    let paths = match &event.kind {
+        EventKind::Rename { from, to } => vec![folder.local_path(from), folder.local_path(to)],
+        _ => vec![folder.local_path(event.path())],
+    };
+
+    let mut pending = EXPECTED.lock().unwrap();
+    pending.retain(|_, (_, at)| at.elapsed() < ECHO_WINDOW);
+
+    let mut echo = true;
+    for path in &paths {
+        match pending.get(path) {
+            Some((expected, _)) if still(expected, path) => (),
+            Some(_) => {
+                pending.remove(path);
+                echo = false;
+            }
+            None => echo = false,
+        }
+    }
+    echo
+}
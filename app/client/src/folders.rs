@@ -0,0 +1,246 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock, atomic::AtomicBool},
+};
+
+use common::{Event, EventKind};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{config::CONFIG, ignore::IgnoreRules, transfer::STAGING_DIR};
+
+/// Where a folder pair is in its sync cycle, shown in the UI
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FolderStatus {
+    pub syncing: bool,
+    /// Local time the last cycle finished
+    pub last_synced: Option<String>,
+}
+
+/// A local folder kept in sync with a folder in the user's remote storage. Each pair has its own
+/// change journal, manifest, feed cursor and ignore rules, and paths inside it are relative to
+/// `local`. Only requests to the server use the remote form.
+pub struct SyncFolder {
+    pub local: PathBuf,
+    /// Remote folder without surrounding slashes, `""` for the whole storage
+    pub remote: String,
+    pub changes_path: PathBuf,
+    pub log_path: PathBuf,
+    pub manifest_path: PathBuf,
+    pub ignore: RwLock<IgnoreRules>,
+    /// Local changes seen while a sync cycle was running, written to the journal afterwards
+    pub pending: tokio::sync::Mutex<Vec<Event>>,
+    /// Set once a full reconcile has run, the change feed is enough after that
+    pub reconciled: AtomicBool,
+    pub status: Mutex<FolderStatus>,
+}
+
+/// Every configured pair. Without `[[folders]]` in the config, `storage_path` syncs the whole
+/// remote storage using the journal paths from the config, as before pairs existed.
+pub static FOLDERS: Lazy<Vec<SyncFolder>> = Lazy::new(|| {
+    if CONFIG.folders.is_empty() {
+        return vec![SyncFolder::new(
+            PathBuf::from(&CONFIG.storage_path),
+            String::new(),
+            Some((&CONFIG.changes_path, &CONFIG.log_path, &CONFIG.manifest_path)),
+        )];
+    }
+
+    CONFIG
+        .folders
+        .iter()
+        .map(|folder| {
+            let remote = folder.remote.replace('\\', "/").trim_matches('/').to_string();
+            SyncFolder::new(PathBuf::from(&folder.local), remote, None)
+        })
+        .collect()
+});
+
+impl SyncFolder {
+    fn new(local: PathBuf, remote: String, journals: Option<(&str, &str, &str)>) -> SyncFolder {
+        let staging = local.join(STAGING_DIR);
+        let (changes_path, log_path, manifest_path) = match journals {
+            Some((changes, logs, manifest)) => (changes.into(), logs.into(), manifest.into()),
+            None => (
+                staging.join("changes.jsonl"),
+                staging.join("logs.jsonl"),
+                staging.join("manifest.json"),
+            ),
+        };
+        let _ = fs::create_dir_all(&staging);
+
+        SyncFolder {
+            ignore: RwLock::new(IgnoreRules::load(&local)),
+            local,
+            remote,
+            changes_path,
+            log_path,
+            manifest_path,
+            pending: tokio::sync::Mutex::new(Vec::new()),
+            reconciled: AtomicBool::new(false),
+            status: Mutex::new(FolderStatus::default()),
+        }
+    }
+
+    /// A pair rooted at `local` with no ignore rules, without touching the config or the disk
+    #[cfg(test)]
+    pub fn for_tests(local: PathBuf) -> SyncFolder {
+        let staging = local.join(STAGING_DIR);
+        SyncFolder {
+            ignore: RwLock::new(IgnoreRules::default()),
+            remote: String::new(),
+            changes_path: staging.join("changes.jsonl"),
+            log_path: staging.join("logs.jsonl"),
+            manifest_path: staging.join("manifest.json"),
+            local,
+            pending: tokio::sync::Mutex::new(Vec::new()),
+            reconciled: AtomicBool::new(false),
+            status: Mutex::new(FolderStatus::default()),
+        }
+    }
+
+    /// Display name for logs and the UI
+    pub fn name(&self) -> String {
+        let remote = if self.remote.is_empty() {
+            "/".to_string()
+        } else {
+            format!("{}/", self.remote)
+        };
+        format!("{} → {}", self.local.display(), remote)
+    }
+
+    pub fn local_path(&self, path: &str) -> PathBuf {
+        self.local.join(path)
+    }
+
+    /// File in this pair's hidden staging folder, never synced itself
+    pub fn staging_path(&self, name: &str) -> PathBuf {
+        self.local.join(STAGING_DIR).join(name)
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.local)
+    }
+
+    /// `path` relative to the local folder with `/` separators
+    pub fn relative_path(&self, path: &Path) -> String {
+        path.strip_prefix(&self.local)
+            .unwrap_or(path)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Where `path` lives in the user's remote storage
+    pub fn remote_path(&self, path: &str) -> String {
+        if self.remote.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}/{}", self.remote, path)
+        }
+    }
+
+    /// `remote` relative to this pair, `None` if it's outside the pair's remote folder
+    pub fn from_remote_path(&self, remote: &str) -> Option<String> {
+        if self.remote.is_empty() {
+            return Some(remote.to_string());
+        }
+        remote
+            .strip_prefix(&self.remote)?
+            .strip_prefix('/')
+            .map(str::to_string)
+    }
+
+    /// The event as the server should record it
+    pub fn to_remote(&self, event: &Event) -> Event {
+        let kind = match &event.kind {
+            EventKind::Create { path } => EventKind::Create {
+                path: self.remote_path(path),
+            },
+            EventKind::Modify { path } => EventKind::Modify {
+                path: self.remote_path(path),
+            },
+            EventKind::Delete { path } => EventKind::Delete {
+                path: self.remote_path(path),
+            },
+            EventKind::Mkdir { path } => EventKind::Mkdir {
+                path: self.remote_path(path),
+            },
+            EventKind::Rename { from, to } => EventKind::Rename {
+                from: self.remote_path(from),
+                to: self.remote_path(to),
+            },
+        };
+        Event { kind, ..event.clone() }
+    }
+
+    /// A change from the server as it applies to this pair, if it touches it at all. A rename
+    /// across the pair's edge is the create or delete this side sees.
+    pub fn from_remote(&self, event: &Event) -> Option<Event> {
+        let kind = match &event.kind {
+            EventKind::Create { path } => EventKind::Create {
+                path: self.from_remote_path(path)?,
+            },
+            EventKind::Modify { path } => EventKind::Modify {
+                path: self.from_remote_path(path)?,
+            },
+            EventKind::Delete { path } => EventKind::Delete {
+                path: self.from_remote_path(path)?,
+            },
+            EventKind::Mkdir { path } => EventKind::Mkdir {
+                path: self.from_remote_path(path)?,
+            },
+            EventKind::Rename { from, to } => {
+                match (self.from_remote_path(from), self.from_remote_path(to)) {
+                    (Some(from), Some(to)) => EventKind::Rename { from, to },
+                    (Some(from), None) => EventKind::Delete { path: from },
+                    (None, Some(to)) => EventKind::Create { path: to },
+                    (None, None) => return None,
+                }
+            }
+        };
+        Some(Event { kind, ..event.clone() })
+    }
+
+    /// Checks `path` against this pair's rules, looking at the disk to tell folders from files. A
+    /// path that no longer exists counts as ignored if it would be as either.
+    pub fn is_ignored(&self, path: &str) -> bool {
+        let rules = self.ignore.read().unwrap();
+        match fs::metadata(self.local_path(path)) {
+            Ok(meta) => rules.is_ignored(path, meta.is_dir()),
+            Err(_) => rules.is_ignored(path, false) || rules.is_ignored(path, true),
+        }
+    }
+
+    pub fn reload_ignore(&self) {
+        let rules = IgnoreRules::load(&self.local);
+        *self.ignore.write().unwrap() = rules;
+    }
+}
+
+/// The pair a local path belongs to, the innermost one if they're nested
+pub fn folder_for(path: &Path) -> Option<&'static SyncFolder> {
+    folder_in(&FOLDERS, path)
+}
+
+/// Pairs named `names` in a temp folder of their own for `test`, leaked like the configured ones
+/// are. Nothing is created on disk.
+#[cfg(test)]
+pub fn test_folders(test: &str, names: &[&str]) -> &'static [SyncFolder] {
+    let root = std::env::temp_dir().join(format!("{}-{}", test, std::process::id()));
+    let folders: Vec<SyncFolder> = names
+        .iter()
+        .map(|name| SyncFolder::for_tests(root.join(name)))
+        .collect();
+    Box::leak(folders.into_boxed_slice())
+}
+
+/// The innermost of `folders` holding `path`
+pub fn folder_in(folders: &'static [SyncFolder], path: &Path) -> Option<&'static SyncFolder> {
+    folders
+        .iter()
+        .filter(|folder| folder.contains(path))
+        .max_by_key(|folder| folder.local.components().count())
+}
@@ -0,0 +1,306 @@
+use std::{fs, path::Path};
+
+use crate::{config::CONFIG, transfer::STAGING_DIR};
+
+/// Per folder ignore file, same syntax as `.gitignore`
+pub const IGNORE_FILE: &str = ".dsyncignore";
+/// Swap and temp files editors save through, ignored unless a rule brings them back
+const EDITOR_TEMP_FILES: [&str; 3] = ["*.swp", "~$*", "*.tmp"];
+
+#[derive(Clone, Debug)]
+struct Rule {
+    /// Folder the rule came from, relative to the storage root, `""` for the root itself
+    base: String,
+    segments: Vec<String>,
+    /// Has a `/` before its end, so it matches from `base` rather than at any depth
+    anchored: bool,
+    dir_only: bool,
+    negated: bool,
+}
+
+impl Rule {
+    fn parse(base: &str, line: &str) -> Option<Rule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.trim_start_matches('/');
+        if line.is_empty() {
+            return None;
+        }
+
+        Some(Rule {
+            base: base.to_string(),
+            segments: line.split('/').map(str::to_string).collect(),
+            anchored,
+            dir_only,
+            negated,
+        })
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let relative = if self.base.is_empty() {
+            path
+        } else {
+            match path.strip_prefix(&self.base) {
+                Some(rest) if rest.starts_with('/') => &rest[1..],
+                _ => return false,
+            }
+        };
+
+        let parts: Vec<&str> = relative.split('/').collect();
+        if self.anchored {
+            match_segments(&self.segments, &parts)
+        } else {
+            parts
+                .last()
+                .is_some_and(|name| match_glob(&self.segments[0], name))
+        }
+    }
+}
+
+/// Matches path segments against pattern segments, where `**` stands for any number of segments
+fn match_segments(pattern: &[String], parts: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => parts.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=parts.len()).any(|skip| match_segments(rest, &parts[skip..]))
+        }
+        Some((first, rest)) => parts
+            .split_first()
+            .is_some_and(|(part, others)| match_glob(first, part) && match_segments(rest, others)),
+    }
+}
+
+/// Matches one segment against `*`, `?`, `[a-z]`, `[!a]` and `\` escapes
+fn match_glob(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    glob_from(&pattern, &name)
+}
+
+fn glob_from(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| glob_from(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && glob_from(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let Some(end) = pattern.iter().skip(2).position(|c| *c == ']').map(|i| i + 2) else {
+                return name.first() == Some(&'[') && glob_from(&pattern[1..], &name[1..]);
+            };
+            let Some(c) = name.first() else {
+                return false;
+            };
+
+            let mut class = &pattern[1..end];
+            let negated = matches!(class.first(), Some('!' | '^'));
+            if negated {
+                class = &class[1..];
+            }
+
+            let mut found = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    found |= (class[i]..=class[i + 2]).contains(c);
+                    i += 3;
+                } else {
+                    found |= class[i] == *c;
+                    i += 1;
+                }
+            }
+
+            found != negated && glob_from(&pattern[end + 1..], &name[1..])
+        }
+        Some('\\') if pattern.len() > 1 => {
+            name.first() == Some(&pattern[1]) && glob_from(&pattern[2..], &name[1..])
+        }
+        Some(c) => name.first() == Some(c) && glob_from(&pattern[1..], &name[1..]),
+    }
+}
+
+/// `.dsyncignore` rules from every folder plus the config's `excluded_folders`
+#[derive(Clone, Debug, Default)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    /// Reads the rules under `root`, skipping folders that are already ignored
+    pub fn load(root: &Path) -> IgnoreRules {
+        let mut rules = IgnoreRules {
+            rules: EDITOR_TEMP_FILES
+                .iter()
+                .copied()
+                .chain(CONFIG.excluded_folders.iter().map(String::as_str))
+                .filter_map(|line| Rule::parse("", line))
+                .collect(),
+        };
+
+        let mut dirs = vec![String::new()];
+        while let Some(dir) = dirs.pop() {
+            let path = root.join(&dir);
+            if let Ok(content) = fs::read_to_string(path.join(IGNORE_FILE)) {
+                rules
+                    .rules
+                    .extend(content.lines().filter_map(|line| Rule::parse(&dir, line)));
+            }
+
+            let Ok(entries) = fs::read_dir(&path) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                if !entry.file_type().is_ok_and(|t| t.is_dir()) {
+                    continue;
+                }
+                let name = entry.file_name().to_string_lossy().to_string();
+                let child = if dir.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", dir, name)
+                };
+                if child != STAGING_DIR && !rules.is_ignored(&child, true) {
+                    dirs.push(child);
+                }
+            }
+        }
+
+        rules
+    }
+
+    /// Whether `path`, relative to the storage root, is kept out of sync. Like git, nothing inside
+    /// an ignored folder can be brought back by a later `!` rule.
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        let mut prefix = String::new();
+        for part in path.split('/') {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(part);
+
+            let dir = prefix.len() < path.len() || is_dir;
+            if self.decide(&prefix, dir) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// The last rule that matches wins
+    fn decide(&self, path: &str, is_dir: bool) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(path, is_dir))
+            .is_some_and(|rule| !rule.negated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rules as if read from ignore files, each line with the folder it came from
+    fn from_lines(lines: &[(&str, &str)]) -> IgnoreRules {
+        IgnoreRules {
+            rules: lines
+                .iter()
+                .filter_map(|(base, line)| Rule::parse(base, line))
+                .collect(),
+        }
+    }
+
+    fn root(lines: &[&str]) -> IgnoreRules {
+        from_lines(&lines.iter().map(|line| ("", *line)).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn single_star_stays_within_a_folder() {
+        let rules = root(&["docs/*.md"]);
+        assert!(rules.is_ignored("docs/a.md", false));
+        assert!(!rules.is_ignored("docs/sub/a.md", false));
+        assert!(!rules.is_ignored("a.md", false));
+    }
+
+    #[test]
+    fn double_star_crosses_folders() {
+        let rules = root(&["docs/**/*.md"]);
+        assert!(rules.is_ignored("docs/a.md", false));
+        assert!(rules.is_ignored("docs/sub/deep/a.md", false));
+        assert!(!rules.is_ignored("other/a.md", false));
+
+        let rules = root(&["**/cache"]);
+        assert!(rules.is_ignored("cache", true));
+        assert!(rules.is_ignored("a/b/cache/file", false));
+    }
+
+    #[test]
+    fn unanchored_patterns_match_at_any_depth() {
+        let rules = root(&["*.log"]);
+        assert!(rules.is_ignored("run.log", false));
+        assert!(rules.is_ignored("a/b/run.log", false));
+        assert!(!rules.is_ignored("run.log.txt", false));
+    }
+
+    #[test]
+    fn leading_slash_anchors_to_the_ignore_file() {
+        let rules = root(&["/build"]);
+        assert!(rules.is_ignored("build", true));
+        assert!(rules.is_ignored("build/out.o", false));
+        assert!(!rules.is_ignored("src/build", true));
+
+        let nested = from_lines(&[("sub", "/tmp")]);
+        assert!(nested.is_ignored("sub/tmp", true));
+        assert!(!nested.is_ignored("tmp", true));
+        assert!(!nested.is_ignored("sub/deeper/tmp", true));
+    }
+
+    #[test]
+    fn trailing_slash_only_matches_folders() {
+        let rules = root(&["logs/"]);
+        assert!(rules.is_ignored("logs", true));
+        assert!(rules.is_ignored("a/logs/today.txt", false));
+        assert!(!rules.is_ignored("logs", false));
+        assert!(!rules.is_ignored("a/logs", false));
+    }
+
+    #[test]
+    fn later_negation_wins() {
+        let rules = root(&["*.txt", "!keep.txt"]);
+        assert!(rules.is_ignored("drop.txt", false));
+        assert!(!rules.is_ignored("keep.txt", false));
+
+        let rules = root(&["!keep.txt", "*.txt"]);
+        assert!(rules.is_ignored("keep.txt", false));
+    }
+
+    #[test]
+    fn negation_cannot_reach_into_an_ignored_folder() {
+        let rules = root(&["build/", "!build/keep.txt"]);
+        assert!(rules.is_ignored("build/keep.txt", false));
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let rules = root(&["# *.txt", "", "   ", "\\#hash", "!"]);
+        assert_eq!(rules.rules.len(), 1);
+        assert!(!rules.is_ignored("a.txt", false));
+        assert!(!rules.is_ignored("# a.txt", false));
+        assert!(rules.is_ignored("#hash", false));
+    }
+}
@@ -4,57 +4,102 @@ use iced::{
     Size,
     window::{self, Position},
 };
-use notify::{
-    Event, EventKind, RecursiveMode, Result, Watcher,
-    event::{AccessKind, CreateKind, ModifyKind},
-};
-use tokio::time::sleep;
-use tungstenite::{WebSocket, stream::MaybeTlsStream};
+use common::{Event as SyncEvent, EventKind as SyncKind, to_line};
+use notify::{Event, RecursiveMode, Result, Watcher};
+use tokio::{sync::mpsc::unbounded_channel, time::sleep};
 
 use std::{
     fs::OpenOptions,
     io::Write,
-    net::TcpStream,
-    path::Path,
-    sync::{
-        Arc, Mutex,
-        atomic::{Ordering},
-        mpsc,
-    },
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
 };
 
+pub mod activity;
+pub mod auth;
 pub mod config;
+pub mod conflict;
 pub mod connection;
+pub mod control;
+pub mod debounce;
+pub mod delta;
+pub mod echo;
+pub mod folders;
+pub mod ignore;
+pub mod manifest;
+pub mod progress;
+pub mod queue;
+pub mod renames;
+pub mod scan;
+pub mod server;
 pub mod setup;
+pub mod throttle;
+pub mod transfer;
 pub mod ui;
 pub mod util;
 
 use crate::{
-    config::CONFIG, connection::{connect_to_ws, create_socket}, ui::Counter, util::{IN_MEMORY_EVENTS, IS_ANALYSING_LOGS, analyse_logs}
+    connection::{Socket, create_socket},
+    folders::{FOLDERS, SyncFolder, folder_for},
+    ignore::IGNORE_FILE,
+    renames::RENAMES,
+    transfer::STAGING_DIR,
+    ui::Counter,
+    util::{IS_ANALYSING_LOGS, SYNC_NOW, analyse_logs},
 };
 
-
 async fn watch() -> Result<()> {
-    let (tx, rx) = mpsc::channel::<Result<Event>>();
-    let mut watcher = notify::recommended_watcher(tx)?;
-    watcher.watch(Path::new(&CONFIG.storage_path), RecursiveMode::Recursive)?;
+    // Received asynchronously, blocking here would starve the sync loop on single core machines
+    let (tx, mut rx) = unbounded_channel::<Result<Event>>();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = tx.send(res);
+    })?;
+    for folder in FOLDERS.iter() {
+        watcher.watch(&folder.local, RecursiveMode::Recursive)?;
+    }
+    loop {
+        // Wakes up while a rename waits for its other half, to send the delete if none comes, and
+        // while changes wait for their path to go quiet
+        let waiting = RENAMES.lock().unwrap().waiting();
+        let res = if waiting || debounce::waiting() {
+            tokio::time::timeout(WATCH_TICK, rx.recv()).await.ok()
+        } else {
+            Some(rx.recv().await)
+        };
+        let expired = RENAMES.lock().unwrap().expired(Instant::now());
+        for (folder, event) in expired {
+            record(folder, event);
+        }
+        for (folder, event) in debounce::ready() {
+            journal(folder, event).await;
+        }
+        let res = match res {
+            Some(Some(res)) => res,
+            Some(None) => break,
+            None => continue,
+        };
 
-    for res in rx {
         match res {
             Ok(event) => {
-                if !(matches!(event.kind, EventKind::Modify(ModifyKind::Any))
-                    && event.paths[0].is_dir())
-                    && !(matches!(event.kind, EventKind::Create(CreateKind::Any)))
-                    && !(matches!(event.kind, EventKind::Access(AccessKind::Open(_)))
-                        || matches!(event.kind, EventKind::Access(AccessKind::Close(_))))
+                let Some(folder) = event.paths.first().and_then(|p| folder_for(p)) else {
+                    continue;
+                };
+                let staging = folder.local.join(STAGING_DIR);
+                if event.paths.iter().any(|p| p.starts_with(&staging)) {
+                    continue;
+                }
+
+                if event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name().is_some_and(|name| name == IGNORE_FILE))
                 {
-                    if IS_ANALYSING_LOGS.load(Ordering::Relaxed) {
-                       let mut events =  IN_MEMORY_EVENTS.lock().await;
-                       events.push(event);
-                    } else {
-                        write_to_changes(event);
-                    }
+                    folder.reload_ignore();
+                }
+
+                let changes = RENAMES.lock().unwrap().convert(&event);
+                for (folder, event) in changes {
+                    record(folder, event);
                 }
             }
             Err(e) => println!("watch error: {:?}", e),
@@ -64,7 +109,50 @@ async fn watch() -> Result<()> {
     Ok(())
 }
 
-fn run_ui(socket: Arc<Mutex<Option<WebSocket<MaybeTlsStream<TcpStream>>>>>) {
+/// Passes a local change on to be journaled once its path goes quiet, unless the path is ignored
+/// or the sync engine made the change itself
+fn record(folder: &'static SyncFolder, event: SyncEvent) {
+    let Some(event) = skip_ignored(folder, event) else {
+        return;
+    };
+    if echo::is_echo(folder, &event) {
+        return;
+    }
+
+    debounce::push(folder, event);
+}
+
+/// Journals a local change, holding it in memory while a sync cycle is running
+async fn journal(folder: &SyncFolder, event: SyncEvent) {
+    if IS_ANALYSING_LOGS.load(Ordering::Relaxed) {
+        folder.pending.lock().await.push(event);
+    } else {
+        write_to_changes(folder, &event);
+    }
+}
+
+/// Drops events for ignored paths. A rename across the boundary turns into the create or delete
+/// the other side sees.
+fn skip_ignored(folder: &SyncFolder, event: SyncEvent) -> Option<SyncEvent> {
+    let SyncKind::Rename { from, to } = &event.kind else {
+        return (!folder.is_ignored(event.path())).then_some(event);
+    };
+
+    let kind = match (folder.is_ignored(from), folder.is_ignored(to)) {
+        (true, true) => return None,
+        (true, false) if folder.local_path(to).is_dir() => SyncKind::Mkdir { path: to.clone() },
+        (true, false) => SyncKind::Create { path: to.clone() },
+        (false, true) => SyncKind::Delete { path: from.clone() },
+        (false, false) => return Some(event),
+    };
+
+    Some(SyncEvent { kind, ..event })
+}
+
+/// How often the watcher looks at waiting renames and changes while there are any
+const WATCH_TICK: Duration = Duration::from_millis(250);
+
+fn run_ui(socket: Arc<Socket>) {
     let icon_bytes = include_bytes!("../logo.png");
     let icon = window::icon::from_file_data(icon_bytes, None).unwrap();
 
@@ -73,68 +161,99 @@ fn run_ui(socket: Arc<Mutex<Option<WebSocket<MaybeTlsStream<TcpStream>>>>>) {
         Counter::update,
         Counter::view,
     )
+    .subscription(Counter::subscription)
     .title("Drive Sync")
     .window(window::Settings {
         position: Position::Centered,
         resizable: false,
-        size: Size::new(300.0, 400.0),
+        size: Size::new(300.0, 560.0),
         icon: Some(icon),
         ..Default::default()
     })
     .run();
 }
 
-fn write_to_changes(event: Event) {
-    println!("Writing change:{:?}", event.kind);
-    let path = &event.paths[0];
+fn write_to_changes(folder: &SyncFolder, event: &SyncEvent) {
+    println!("Writing change:{}", event);
     let mut file = OpenOptions::new()
+        .create(true)
         .append(true)
-        .open(&CONFIG.changes_path)
+        .open(&folder.changes_path)
         .unwrap();
 
-    let time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis();
-
-    let mut path = path.display().to_string();
-
-    path = path.replace("\\", "/");
-
-    let storage = CONFIG.storage_path.replace("\\", "/");
-    path = path
-        .strip_prefix(&format!("{}/", storage))
-        .unwrap_or(&path)
-        .to_string();
+    let _ = file.write(to_line(event).as_bytes());
+}
 
-    path = path.trim_start_matches('/').to_string();
+/// Writes changes still held in memory to the journals, so they are sent after a restart
+async fn flush_pending() {
+    for folder in FOLDERS.iter() {
+        for event in folder.pending.lock().await.drain(..) {
+            write_to_changes(folder, &event);
+        }
+    }
+    // Moved out of the pair as far as anyone will know, they go in with the changes still waiting
+    let moved_out = RENAMES.lock().unwrap().flush();
+    for (folder, event) in moved_out {
+        record(folder, event);
+    }
+    for (folder, event) in debounce::drain() {
+        write_to_changes(folder, &event);
+    }
+}
 
-    let changes = format!(
-        "[[events]]\nevent_type = \"{:?}\"\npath = \"{}\"\ntime = {:?}\n",
-        event.kind, path, time,
-    );
+/// Resolves on SIGTERM, what systemd sends to stop a service, or Ctrl-C
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
 
-    let _ = file.write(changes.as_bytes());
+        let mut term = signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM");
+        tokio::select! {
+            _ = term.recv() => (),
+            _ = tokio::signal::ctrl_c() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
 
 #[tokio::main]
 async fn main() {
-    let t2 = tokio::spawn(watch());
-    let t1 = tokio::spawn(async {
+    tokio::spawn(async {
+        shutdown_signal().await;
+        println!("Shutting down");
+        flush_pending().await;
+        std::process::exit(0);
+    });
+
+    let sync = tokio::spawn(async {
+        // What changed while the client wasn't running is journalled before the watcher takes over
+        scan::startup_scan().await;
+        tokio::spawn(async {
+            // Local changes would only be picked up by the next startup scan
+            if let Err(e) = watch().await {
+                println!("Couldn't watch the sync folders: {}", e);
+                activity::failed("Watching the sync folders", None, Some(&e.to_string()));
+            }
+        });
+
         loop {
-            if !IS_ANALYSING_LOGS.load(Ordering::Relaxed) {
-                analyse_logs().await;
-                sleep(Duration::from_secs(100)).await;
+            analyse_logs().await;
+            // Polling is the fallback, pushes over the websocket wake this up straight away
+            tokio::select! {
+                _ = sleep(Duration::from_secs(100)) => (),
+                _ = SYNC_NOW.notified() => (),
             }
-            sleep(Duration::from_secs(10)).await;
         }
     });
 
     let socket = create_socket();
-    let sock_clone = socket.clone();
-    connect_to_ws(sock_clone);
+    tokio::spawn(control::serve(socket.clone()));
 
-    run_ui(socket);
+    // Headless keeps running in the foreground until it's signalled
+    if !config::headless() {
+        run_ui(socket);
+    }
 
-    let _ = tokio::join!(t1, t2);
+    let _ = sync.await;
 }
@@ -0,0 +1,54 @@
+use std::{collections::BTreeSet, path::Path};
+
+use common::manifest::{self, Manifest};
+
+use crate::{ignore::IgnoreRules, transfer::STAGING_DIR};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Upload(String),
+    Download(String),
+    DeleteRemote(String),
+    DeleteLocal(String),
+    /// Changed on both sides since the last sync
+    Conflict(String),
+}
+
+/// Builds the manifest of a sync folder, leaving out what `rules` ignores and the staging folder
+/// at its root.
+pub fn build_manifest(root: &Path, cache: &Manifest, rules: &IgnoreRules) -> Manifest {
+    manifest::build_manifest(root, cache, |relative, is_dir| {
+        (is_dir && relative == STAGING_DIR) || rules.is_ignored(relative, is_dir)
+    })
+}
+
+/// Diffs the local and remote manifests against `base`, the state both sides agreed on after the
+/// last successful reconciliation, and works out which side each change has to be applied to.
+pub fn plan(local: &Manifest, remote: &Manifest, base: &Manifest) -> Vec<Action> {
+    let paths: BTreeSet<&String> = local.files.keys().chain(remote.files.keys()).collect();
+    let mut actions = Vec::new();
+
+    for path in paths {
+        let l = local.files.get(path);
+        let r = remote.files.get(path);
+        let b = base.files.get(path).map(|b| &b.hash);
+
+        let action = match (l, r) {
+            (Some(l), Some(r)) if l.hash == r.hash => None,
+            (Some(l), None) if b == Some(&l.hash) => Some(Action::DeleteLocal(path.clone())),
+            (Some(_), None) => Some(Action::Upload(path.clone())),
+            (None, Some(r)) if b == Some(&r.hash) => Some(Action::DeleteRemote(path.clone())),
+            (None, Some(_)) => Some(Action::Download(path.clone())),
+            (Some(l), Some(_)) if b == Some(&l.hash) => Some(Action::Download(path.clone())),
+            (Some(_), Some(r)) if b == Some(&r.hash) => Some(Action::Upload(path.clone())),
+            (Some(_), Some(_)) => Some(Action::Conflict(path.clone())),
+            (None, None) => None,
+        };
+
+        if let Some(action) = action {
+            actions.push(action);
+        }
+    }
+
+    actions
+}
@@ -0,0 +1,236 @@
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferState {
+    Queued,
+    Active,
+    Done,
+    Failed,
+}
+
+/// One file sent or fetched during the current sync cycle
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    /// Path in the remote storage, unique across folder pairs
+    pub path: String,
+    pub direction: Direction,
+    /// 0 until known, downloads only learn it from the response
+    pub size: u64,
+    pub done: u64,
+    pub state: TransferState,
+}
+
+/// What the current or last sync cycle transferred, published to the UI as it changes
+#[derive(Clone, Debug, Default)]
+pub struct Progress {
+    pub transfers: Vec<Transfer>,
+    /// When the first byte of this cycle moved, for throughput
+    started: Option<Instant>,
+}
+
+impl Progress {
+    pub fn files_total(&self) -> usize {
+        self.transfers.len()
+    }
+
+    pub fn files_done(&self) -> usize {
+        self.transfers
+            .iter()
+            .filter(|t| matches!(t.state, TransferState::Done | TransferState::Failed))
+            .count()
+    }
+
+    pub fn bytes_total(&self) -> u64 {
+        self.transfers.iter().map(|t| t.size.max(t.done)).sum()
+    }
+
+    pub fn bytes_done(&self) -> u64 {
+        self.transfers.iter().map(|t| t.done).sum()
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.files_done() < self.files_total()
+    }
+
+    /// The file being transferred right now, the latest one if there are several
+    pub fn current(&self) -> Option<&Transfer> {
+        self.transfers
+            .iter()
+            .rev()
+            .find(|t| t.state == TransferState::Active)
+    }
+
+    /// Share of the cycle done, by bytes when sizes are known and by files otherwise
+    pub fn fraction(&self) -> f32 {
+        let total = self.bytes_total();
+        if total > 0 {
+            return self.bytes_done() as f32 / total as f32;
+        }
+        if self.files_total() > 0 {
+            return self.files_done() as f32 / self.files_total() as f32;
+        }
+        0.0
+    }
+
+    /// Average bytes per second since the cycle's first transfer started
+    pub fn throughput(&self) -> f64 {
+        let Some(started) = self.started else {
+            return 0.0;
+        };
+        let elapsed = started.elapsed().as_secs_f64();
+        if elapsed < 0.5 {
+            return 0.0;
+        }
+        self.bytes_done() as f64 / elapsed
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let throughput = self.throughput();
+        if throughput <= 0.0 || !self.is_busy() {
+            return None;
+        }
+        let left = self.bytes_total().saturating_sub(self.bytes_done());
+        Some(Duration::from_secs_f64(left as f64 / throughput))
+    }
+}
+
+/// Latest progress, the UI subscribes to it
+pub static PROGRESS: Lazy<watch::Sender<Progress>> =
+    Lazy::new(|| watch::channel(Progress::default()).0);
+
+fn update(f: impl FnOnce(&mut Progress)) {
+    PROGRESS.send_modify(f);
+}
+
+/// Forgets what finished in earlier cycles
+pub fn new_cycle() {
+    update(|progress| {
+        progress.transfers.retain(|t| t.state == TransferState::Active);
+        if progress.transfers.is_empty() {
+            progress.started = None;
+        }
+    });
+}
+
+/// Drops files that were queued but turned out not to need transferring
+pub fn end_cycle() {
+    update(|progress| progress.transfers.retain(|t| t.state != TransferState::Queued));
+}
+
+/// Adds a file the cycle is about to transfer, so the totals include it before it starts
+pub fn enqueue(path: &str, direction: Direction, size: u64) {
+    update(|progress| {
+        if !progress
+            .transfers
+            .iter()
+            .any(|t| t.path == path && matches!(t.state, TransferState::Queued | TransferState::Active))
+        {
+            progress.transfers.push(Transfer {
+                path: path.to_string(),
+                direction,
+                size,
+                done: 0,
+                state: TransferState::Queued,
+            });
+        }
+    });
+}
+
+fn with_transfer(progress: &mut Progress, path: &str, direction: Direction) -> usize {
+    let found = progress.transfers.iter().position(|t| {
+        t.path == path
+            && t.direction == direction
+            && matches!(t.state, TransferState::Queued | TransferState::Active)
+    });
+    match found {
+        Some(i) => i,
+        None => {
+            progress.transfers.push(Transfer {
+                path: path.to_string(),
+                direction,
+                size: 0,
+                done: 0,
+                state: TransferState::Queued,
+            });
+            progress.transfers.len() - 1
+        }
+    }
+}
+
+pub fn start(path: &str, direction: Direction, size: u64) {
+    update(|progress| {
+        progress.started.get_or_insert_with(Instant::now);
+        let i = with_transfer(progress, path, direction);
+        let transfer = &mut progress.transfers[i];
+        transfer.state = TransferState::Active;
+        transfer.done = 0;
+        if size > 0 {
+            transfer.size = size;
+        }
+    });
+}
+
+/// Sets the size once a download's response says how big the file is
+pub fn resize(path: &str, size: u64) {
+    update(|progress| {
+        if let Some(transfer) = progress
+            .transfers
+            .iter_mut()
+            .find(|t| t.path == path && t.state == TransferState::Active)
+        {
+            transfer.size = size;
+        }
+    });
+}
+
+pub fn advance(path: &str, bytes: u64) {
+    update(|progress| {
+        if let Some(transfer) = progress
+            .transfers
+            .iter_mut()
+            .find(|t| t.path == path && t.state == TransferState::Active)
+        {
+            transfer.done += bytes;
+        }
+    });
+}
+
+pub fn finish(path: &str, ok: bool) {
+    update(|progress| {
+        if let Some(transfer) = progress
+            .transfers
+            .iter_mut()
+            .find(|t| t.path == path && t.state == TransferState::Active)
+        {
+            if ok {
+                transfer.done = transfer.size.max(transfer.done);
+                transfer.state = TransferState::Done;
+            } else {
+                transfer.state = TransferState::Failed;
+            }
+        }
+    });
+}
+
+/// `1.2 MB` style sizes for the UI
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+    let mut size = bytes;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
@@ -0,0 +1,411 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use common::{Event, EventKind, manifest::Manifest};
+use futures_util::{StreamExt, stream::FuturesUnordered};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    activity::{self, Retry},
+    config::CONFIG,
+    connection::edit_server_side,
+    folders::SyncFolder,
+};
+
+/// Wait before the first retry, doubled after every failed attempt
+const FIRST_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// How sending a change went
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sent {
+    Done,
+    Failed,
+    /// Not tried because an earlier change to the same path failed
+    Held,
+}
+
+/// A change the server didn't take, tried again once `next_attempt` has passed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueuedRetry {
+    pub event: Event,
+    /// Failed attempts so far
+    pub attempts: u32,
+    /// Seconds since the epoch
+    pub next_attempt: u64,
+    /// Waiting for an earlier change to the same path rather than for a time, goes with it
+    #[serde(default)]
+    pub held: bool,
+}
+
+/// Paths a change has to wait for earlier changes to, the destination too for a rename
+fn touched(event: &Event) -> Vec<&str> {
+    match &event.kind {
+        EventKind::Rename { from, to } => vec![from, to],
+        _ => vec![event.path()],
+    }
+}
+
+/// `a`, `a/b` and `a/b/c` for `a/b/c`
+fn with_ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/')
+        .map(move |(i, _)| &path[..i])
+        .chain(std::iter::once(path))
+}
+
+/// Sends the changes to the server, up to `transfer_concurrency` at a time. A change only starts
+/// once every earlier change to the same path, a folder above it or anything inside it is done, and
+/// is held back if one of those failed.
+pub async fn send_events(folder: &SyncFolder, events: &[Event], base: &Mutex<Manifest>) -> Vec<Sent> {
+    let concurrency = CONFIG.transfer_concurrency.max(1);
+    send_in_order(events, concurrency, |i| edit_server_side(folder, &events[i], base)).await
+}
+
+/// How many earlier changes each change waits for, and which later changes wait for each one
+fn dependencies(events: &[Event]) -> (Vec<usize>, Vec<Vec<usize>>) {
+    let count = events.len();
+    let mut waiting = vec![0usize; count];
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); count];
+    // Latest change at a path, and the changes below a path since the path itself last changed
+    let mut latest: HashMap<&str, usize> = HashMap::new();
+    let mut below: HashMap<&str, Vec<usize>> = HashMap::new();
+
+    for (i, event) in events.iter().enumerate() {
+        let mut deps = Vec::new();
+        for path in touched(event) {
+            deps.extend(with_ancestors(path).filter_map(|p| latest.get(p).copied()));
+            deps.extend(below.remove(path).unwrap_or_default());
+        }
+        deps.sort_unstable();
+        deps.dedup();
+
+        for path in touched(event) {
+            latest.insert(path, i);
+            for ancestor in with_ancestors(path).filter(|p| *p != path) {
+                below.entry(ancestor).or_default().push(i);
+            }
+        }
+
+        waiting[i] = deps.len();
+        for dep in deps {
+            dependents[dep].push(i);
+        }
+    }
+
+    (waiting, dependents)
+}
+
+/// Runs `send` for each change once the ones it depends on are done, `concurrency` at a time
+async fn send_in_order<F, Fut>(events: &[Event], concurrency: usize, send: F) -> Vec<Sent>
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let (mut waiting, dependents) = dependencies(events);
+    let mut results = vec![Sent::Held; events.len()];
+    let mut ready: VecDeque<usize> = (0..events.len()).filter(|&i| waiting[i] == 0).collect();
+    let mut running = FuturesUnordered::new();
+
+    loop {
+        while running.len() < concurrency
+            && let Some(i) = ready.pop_front()
+        {
+            let sending = send(i);
+            running.push(async move { (i, sending.await) });
+        }
+
+        let Some((i, done)) = running.next().await else {
+            break;
+        };
+        results[i] = if done { Sent::Done } else { Sent::Failed };
+
+        // What depends on a failed change stays held, along with everything depending on that
+        if !done {
+            continue;
+        }
+        for &dependent in &dependents[i] {
+            waiting[dependent] -= 1;
+            if waiting[dependent] == 0 {
+                ready.push_back(dependent);
+            }
+        }
+    }
+
+    results
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn load(folder: &SyncFolder, name: &str) -> Vec<QueuedRetry> {
+    fs::read_to_string(folder.staging_path(name))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save(folder: &SyncFolder, name: &str, entries: &[QueuedRetry]) {
+    let _ = fs::create_dir_all(folder.staging_path(""));
+    if let Ok(content) = serde_json::to_string(entries) {
+        let _ = fs::write(folder.staging_path(name), content);
+    }
+}
+
+/// Changes waiting for their next attempt, persisted so a restart doesn't lose them
+pub fn retries(folder: &SyncFolder) -> Vec<QueuedRetry> {
+    load(folder, "retries.json")
+}
+
+/// Changes given up on after `max_retries` attempts
+pub fn dead_letters(folder: &SyncFolder) -> Vec<QueuedRetry> {
+    load(folder, "dead_letter.json")
+}
+
+/// Whether one change has to wait for the other, when they touch the same path or one is inside
+/// the other
+pub fn overlaps(a: &Event, b: &Event) -> bool {
+    let related = |p: &str, q: &str| {
+        p == q || p.starts_with(&format!("{}/", q)) || q.starts_with(&format!("{}/", p))
+    };
+    touched(a)
+        .iter()
+        .any(|p| touched(b).iter().any(|q| related(p, q)))
+}
+
+/// Takes the retries that are due, and those for paths `events` change again. Earlier retries the
+/// taken ones would overtake come along too, so changes still reach the server in order, and so do
+/// later ones held back by them.
+pub fn take_due(folder: &SyncFolder, events: &[Event]) -> Vec<QueuedRetry> {
+    let now = now();
+    let retries = retries(folder);
+    let mut wanted: Vec<bool> = retries
+        .iter()
+        .map(|retry| {
+            (!retry.held && retry.next_attempt <= now)
+                || events.iter().any(|event| overlaps(event, &retry.event))
+        })
+        .collect();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..retries.len() {
+            for j in i + 1..retries.len() {
+                if wanted[i] == wanted[j] || !overlaps(&retries[i].event, &retries[j].event) {
+                    continue;
+                }
+                if wanted[j] || retries[j].held {
+                    wanted[i] = true;
+                    wanted[j] = true;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    if !wanted.contains(&true) {
+        return Vec::new();
+    }
+    let (due, waiting): (Vec<_>, Vec<_>) = retries
+        .into_iter()
+        .zip(wanted)
+        .partition(|(_, wanted)| *wanted);
+    save(
+        folder,
+        "retries.json",
+        &waiting.into_iter().map(|(retry, _)| retry).collect::<Vec<_>>(),
+    );
+    due.into_iter().map(|(retry, _)| retry).collect()
+}
+
+/// Queues what didn't go through again with a longer wait each time, or moves it to the dead
+/// letter file once it has failed `max_retries` times
+pub fn reschedule(folder: &SyncFolder, sent: Vec<(QueuedRetry, Sent)>) {
+    let (retries, dead) = settle(retries(folder), sent, CONFIG.max_retries, now());
+    save(folder, "retries.json", &retries);
+
+    if dead.is_empty() {
+        return;
+    }
+    for retry in &dead {
+        activity::gave_up(folder, &retry.event, retry.attempts, Retry::Send(retry.event.clone()));
+    }
+    let mut dead_letters = dead_letters(folder);
+    dead_letters.extend(dead);
+    save(folder, "dead_letter.json", &dead_letters);
+}
+
+/// Adds what was sent to `retries` unless it went through, returning the retries left and the
+/// changes given up on
+fn settle(
+    mut retries: Vec<QueuedRetry>,
+    sent: Vec<(QueuedRetry, Sent)>,
+    max_retries: u32,
+    now: u64,
+) -> (Vec<QueuedRetry>, Vec<QueuedRetry>) {
+    let mut dead = Vec::new();
+
+    for (mut retry, result) in sent {
+        match result {
+            Sent::Done => continue,
+            // Goes again with what it waited on, without using up an attempt
+            Sent::Held => retry.held = true,
+            Sent::Failed => {
+                retry.held = false;
+                retry.attempts += 1;
+                if retry.attempts >= max_retries {
+                    dead.push(retry);
+                    continue;
+                }
+                let factor = 2u32.saturating_pow(retry.attempts - 1).min(1024);
+                let backoff = (FIRST_BACKOFF * factor).min(MAX_BACKOFF);
+                retry.next_attempt = now + backoff.as_secs();
+            }
+        }
+        retries.push(retry);
+    }
+
+    // Whatever was held back by a change given up on can't go before it either
+    let mut i = 0;
+    while i < retries.len() {
+        if retries[i].held && dead.iter().any(|d| overlaps(&d.event, &retries[i].event)) {
+            dead.push(retries.remove(i));
+        } else {
+            i += 1;
+        }
+    }
+
+    (retries, dead)
+}
+
+/// Forgets a dead letter once it's been retried by hand
+pub fn revive(folder: &SyncFolder, event: &Event) {
+    let mut dead_letters = dead_letters(folder);
+    let count = dead_letters.len();
+    dead_letters.retain(|retry| retry.event.kind != event.kind);
+    if dead_letters.len() != count {
+        save(folder, "dead_letter.json", &dead_letters);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modify(path: &str) -> Event {
+        Event::new(EventKind::Modify { path: path.to_string() })
+    }
+
+    fn rename(from: &str, to: &str) -> Event {
+        Event::new(EventKind::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+    }
+
+    fn retry(event: Event, attempts: u32, held: bool) -> QueuedRetry {
+        QueuedRetry {
+            event,
+            attempts,
+            next_attempt: 0,
+            held,
+        }
+    }
+
+    /// Sends `events` one at a time, failing the ones in `failing`, and returns the results along
+    /// with the order they were sent in
+    async fn send(events: &[Event], failing: &[usize]) -> (Vec<Sent>, Vec<usize>) {
+        let order = Mutex::new(Vec::new());
+        let results = send_in_order(events, 1, |i| {
+            order.lock().unwrap().push(i);
+            let done = !failing.contains(&i);
+            async move { done }
+        })
+        .await;
+        (results, order.into_inner().unwrap())
+    }
+
+    #[test]
+    fn rename_waits_for_a_modify_of_the_same_path() {
+        let events = [modify("a.txt"), modify("other.txt"), rename("a.txt", "b.txt")];
+        let (waiting, dependents) = dependencies(&events);
+        assert_eq!(waiting, [0, 0, 1]);
+        assert_eq!(dependents[0], [2]);
+        assert!(dependents[1].is_empty());
+    }
+
+    #[test]
+    fn folder_renames_are_ordered_with_changes_inside() {
+        let events = [
+            rename("docs", "papers"),
+            modify("papers/a.txt"),
+            modify("papers/b.txt"),
+            rename("papers", "archive"),
+        ];
+        let (waiting, dependents) = dependencies(&events);
+        assert_eq!(waiting, [0, 1, 1, 3]);
+        assert_eq!(dependents[0], [1, 2, 3]);
+        assert_eq!(dependents[1], [3]);
+    }
+
+    #[tokio::test]
+    async fn unrelated_changes_all_go_through() {
+        let events = [modify("a.txt"), modify("b.txt"), rename("a.txt", "c.txt")];
+        let (results, order) = send(&events, &[]).await;
+        assert_eq!(results, [Sent::Done; 3]);
+        assert!(order.iter().position(|&i| i == 0) < order.iter().position(|&i| i == 2));
+    }
+
+    #[tokio::test]
+    async fn failed_change_holds_its_dependents() {
+        let events = [
+            modify("a.txt"),
+            rename("a.txt", "b.txt"),
+            modify("b.txt"),
+            modify("other.txt"),
+        ];
+        let (results, order) = send(&events, &[0]).await;
+        assert_eq!(results, [Sent::Failed, Sent::Held, Sent::Held, Sent::Done]);
+        assert_eq!(order, [0, 3]);
+    }
+
+    #[test]
+    fn failures_back_off_and_held_changes_keep_their_attempts() {
+        let sent = vec![
+            (retry(modify("a.txt"), 0, false), Sent::Failed),
+            (retry(rename("a.txt", "b.txt"), 0, false), Sent::Held),
+            (retry(modify("done.txt"), 0, false), Sent::Done),
+        ];
+        let (retries, dead) = settle(Vec::new(), sent, 8, 1000);
+        assert!(dead.is_empty());
+        assert_eq!(retries.len(), 2);
+        assert_eq!(retries[0].attempts, 1);
+        assert_eq!(retries[0].next_attempt, 1000 + FIRST_BACKOFF.as_secs());
+        assert!(!retries[0].held);
+        assert_eq!(retries[1].attempts, 0);
+        assert!(retries[1].held);
+    }
+
+    #[test]
+    fn held_retry_is_given_up_with_the_change_it_waited_on() {
+        let waiting = vec![
+            retry(rename("a.txt", "b.txt"), 0, true),
+            retry(modify("other.txt"), 1, false),
+        ];
+        let sent = vec![(retry(modify("a.txt"), 7, false), Sent::Failed)];
+        let (retries, dead) = settle(waiting, sent, 8, 1000);
+
+        let dead: Vec<&EventKind> = dead.iter().map(|d| &d.event.kind).collect();
+        assert_eq!(dead, [&modify("a.txt").kind, &rename("a.txt", "b.txt").kind]);
+        assert_eq!(retries.len(), 1);
+        assert_eq!(retries[0].event.kind, modify("other.txt").kind);
+    }
+}
@@ -0,0 +1,403 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use common::{Event as SyncEvent, EventKind as SyncKind};
+use notify::{
+    Event, EventKind,
+    event::{CreateKind, ModifyKind, RenameMode},
+};
+use once_cell::sync::Lazy;
+
+use crate::folders::{FOLDERS, SyncFolder, folder_in};
+
+/// How long the "from" half of a rename waits for its "to" before it's taken as moved out
+const RENAME_WAIT: Duration = Duration::from_millis(500);
+
+/// A change to one of the pairs
+pub type Change = (&'static SyncFolder, SyncEvent);
+
+/// Pairs up the two halves of a rename. Platforms report a rename as a "from" event followed by a
+/// "to" event, tagged with the same tracker cookie where they can (inotify), and some with a
+/// combined event after that too. A "from" left without its "to" was moved out of the storage
+/// folders and a "to" without a "from" was moved in. The halves can be in different pairs.
+pub struct RenameTracker {
+    /// Pairs the paths in events are looked up in
+    folders: &'static [SyncFolder],
+    /// "from" halves waiting for their "to" by cookie, `None` on platforms without one
+    from: HashMap<Option<usize>, (&'static SyncFolder, String, Instant)>,
+    /// Latest rename, its combined event and the moved folder's own "from" are dropped
+    paired: Option<(PathBuf, PathBuf)>,
+}
+
+/// Shared with the shutdown handler, which flushes the halves still waiting
+pub static RENAMES: Lazy<Mutex<RenameTracker>> =
+    Lazy::new(|| Mutex::new(RenameTracker::new(&FOLDERS)));
+
+impl RenameTracker {
+    pub fn new(folders: &'static [SyncFolder]) -> RenameTracker {
+        RenameTracker {
+            folders,
+            from: HashMap::new(),
+            paired: None,
+        }
+    }
+
+    pub fn waiting(&self) -> bool {
+        !self.from.is_empty()
+    }
+
+    /// Deletes for the "from" halves matching `done`, which can't wait any longer
+    fn take_from(
+        &mut self,
+        done: impl Fn(&SyncFolder, &str, &Instant) -> bool,
+    ) -> Vec<Change> {
+        let cookies: Vec<Option<usize>> = self
+            .from
+            .iter()
+            .filter(|(_, (folder, path, at))| done(folder, path, at))
+            .map(|(cookie, _)| *cookie)
+            .collect();
+        cookies
+            .into_iter()
+            .filter_map(|cookie| self.from.remove(&cookie))
+            .map(|(folder, path, _)| (folder, SyncEvent::new(SyncKind::Delete { path })))
+            .collect()
+    }
+
+    /// Deletes for the "from" halves no "to" came for in time
+    pub fn expired(&mut self, now: Instant) -> Vec<Change> {
+        self.take_from(|_, _, at| now.saturating_duration_since(*at) >= RENAME_WAIT)
+    }
+
+    /// Deletes for every "from" half still waiting, no "to" can come for them anymore
+    pub fn flush(&mut self) -> Vec<Change> {
+        self.take_from(|_, _, _| true)
+    }
+
+    /// The changes a watcher event adds up to, once its rename halves are paired up
+    pub fn convert(&mut self, event: &Event) -> Vec<Change> {
+        let path = &event.paths[0];
+        let Some(folder) = folder_in(self.folders, path) else {
+            return Vec::new();
+        };
+        let relative = folder.relative_path(path);
+        let mut changes = Vec::new();
+
+        // Whatever left from this path, or above or inside it, has to go before a change to it
+        if !matches!(
+            event.kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Both))
+        ) {
+            changes.extend(self.take_from(|from_folder, from, _| {
+                std::ptr::eq(from_folder, folder)
+                    && (*from == relative
+                        || relative.starts_with(&format!("{}/", from))
+                        || from.starts_with(&format!("{}/", relative)))
+            }));
+        }
+
+        let kind = match event.kind {
+            EventKind::Create(CreateKind::Folder) => Some(SyncKind::Mkdir { path: relative }),
+            EventKind::Create(_) if path.is_dir() => Some(SyncKind::Mkdir { path: relative }),
+            EventKind::Create(_) => Some(SyncKind::Create { path: relative }),
+            // A moved folder's own watch reports it leaving too, without a cookie
+            EventKind::Modify(ModifyKind::Name(RenameMode::From))
+                if event.tracker().is_none()
+                    && self.paired.as_ref().is_some_and(|(from, _)| from == path) =>
+            {
+                None
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                let waiting = (folder, relative, Instant::now());
+                if let Some((folder, path, _)) = self.from.insert(event.tracker(), waiting) {
+                    changes.push((folder, SyncEvent::new(SyncKind::Delete { path })));
+                }
+                None
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                let from = self.from.remove(&event.tracker());
+                if let Some((from_folder, from, _)) = &from {
+                    self.paired = Some((from_folder.local_path(from), path.clone()));
+                }
+                changes.extend(moved(from, folder, path));
+                None
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                let paths = (event.paths[0].clone(), event.paths[1].clone());
+                if self.paired.as_ref() != Some(&paths) {
+                    let from = folder_in(self.folders, &paths.0).map(|from_folder| {
+                        (from_folder, from_folder.relative_path(&paths.0), Instant::now())
+                    });
+                    if let Some(to_folder) = folder_in(self.folders, &paths.1) {
+                        changes.extend(moved(from, to_folder, &paths.1));
+                    }
+                    self.paired = Some(paths);
+                }
+                None
+            }
+            EventKind::Modify(ModifyKind::Name(_)) if path.exists() => {
+                Some(SyncKind::Modify { path: relative })
+            }
+            EventKind::Modify(ModifyKind::Name(_)) => Some(SyncKind::Delete { path: relative }),
+            EventKind::Modify(_) if path.is_dir() => None,
+            EventKind::Modify(_) => Some(SyncKind::Modify { path: relative }),
+            EventKind::Remove(_) => Some(SyncKind::Delete { path: relative }),
+            _ => None,
+        };
+
+        changes.extend(kind.map(|kind| (folder, SyncEvent::new(kind))));
+        changes
+    }
+}
+
+/// `to` arriving in `folder`, from `from` if that's known. One rename within a pair, a folder
+/// moved as a whole, otherwise a delete on one side and what was moved in on the other.
+fn moved(
+    from: Option<(&'static SyncFolder, String, Instant)>,
+    folder: &'static SyncFolder,
+    to: &Path,
+) -> Vec<Change> {
+    let mut changes = Vec::new();
+    match from {
+        Some((from_folder, from, _)) if std::ptr::eq(from_folder, folder) => {
+            let to = folder.relative_path(to);
+            return vec![(folder, SyncEvent::new(SyncKind::Rename { from, to }))];
+        }
+        Some((from_folder, path, _)) => {
+            changes.push((from_folder, SyncEvent::new(SyncKind::Delete { path })));
+        }
+        None => (),
+    }
+    changes.extend(
+        moved_in(folder, to)
+            .into_iter()
+            .map(|kind| (folder, SyncEvent::new(kind))),
+    );
+    changes
+}
+
+/// What moving `path` into the pair from outside adds: the file, or the folder and everything in
+/// it, since no events come for what was already inside
+fn moved_in(folder: &SyncFolder, path: &Path) -> Vec<SyncKind> {
+    if !path.is_dir() {
+        return vec![SyncKind::Create {
+            path: folder.relative_path(path),
+        }];
+    }
+
+    let mut kinds = vec![SyncKind::Mkdir {
+        path: folder.relative_path(path),
+    }];
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    entries.sort();
+    for entry in entries {
+        kinds.extend(moved_in(folder, &entry));
+    }
+    kinds
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::folders::test_folders;
+
+    /// Pairs rooted at fresh, empty folders
+    fn folders(test: &str, names: &[&str]) -> &'static [SyncFolder] {
+        let folders = test_folders(&format!("renames-{}", test), names);
+        cleanup(folders);
+        for folder in folders {
+            fs::create_dir_all(&folder.local).unwrap();
+        }
+        folders
+    }
+
+    fn cleanup(folders: &[SyncFolder]) {
+        let _ = fs::remove_dir_all(folders[0].local.parent().unwrap());
+    }
+
+    fn rename(mode: RenameMode, path: PathBuf, cookie: Option<usize>) -> Event {
+        let event = Event::new(EventKind::Modify(ModifyKind::Name(mode))).add_path(path);
+        match cookie {
+            Some(cookie) => event.set_tracker(cookie),
+            None => event,
+        }
+    }
+
+    fn kinds(changes: Vec<Change>) -> Vec<SyncKind> {
+        changes.into_iter().map(|(_, event)| event.kind).collect()
+    }
+
+    fn delete(path: &str) -> SyncKind {
+        SyncKind::Delete {
+            path: path.to_string(),
+        }
+    }
+
+    fn renamed(from: &str, to: &str) -> SyncKind {
+        SyncKind::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    #[test]
+    fn halves_with_the_same_cookie_pair_up() {
+        let folders = folders("pair", &["sync"]);
+        let root = &folders[0].local;
+        fs::write(root.join("b.txt"), "moved").unwrap();
+        let mut tracker = RenameTracker::new(folders);
+
+        let from = rename(RenameMode::From, root.join("a.txt"), Some(1));
+        assert!(tracker.convert(&from).is_empty());
+        assert!(tracker.waiting());
+
+        let to = rename(RenameMode::To, root.join("b.txt"), Some(1));
+        assert_eq!(kinds(tracker.convert(&to)), [renamed("a.txt", "b.txt")]);
+        assert!(!tracker.waiting());
+
+        // The combined event some platforms send after the halves adds nothing
+        let both = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(root.join("a.txt"))
+            .add_path(root.join("b.txt"));
+        assert!(tracker.convert(&both).is_empty());
+        cleanup(folders);
+    }
+
+    #[test]
+    fn unpaired_from_becomes_a_delete_once_the_wait_is_over() {
+        let folders = folders("timeout", &["sync"]);
+        let mut tracker = RenameTracker::new(folders);
+        let start = Instant::now();
+
+        let from = rename(RenameMode::From, folders[0].local.join("gone.txt"), Some(2));
+        assert!(tracker.convert(&from).is_empty());
+        assert!(tracker.expired(start).is_empty());
+        assert_eq!(
+            kinds(tracker.expired(start + RENAME_WAIT * 2)),
+            [delete("gone.txt")]
+        );
+        assert!(!tracker.waiting());
+        cleanup(folders);
+    }
+
+    #[test]
+    fn flush_gives_up_on_every_waiting_half() {
+        let folders = folders("flush", &["sync"]);
+        let mut tracker = RenameTracker::new(folders);
+
+        for (cookie, name) in [(3, "a.txt"), (4, "b.txt")] {
+            let from = rename(RenameMode::From, folders[0].local.join(name), Some(cookie));
+            tracker.convert(&from);
+        }
+        let mut flushed = kinds(tracker.flush());
+        flushed.sort_by_key(|kind| format!("{:?}", kind));
+        assert_eq!(flushed, [delete("a.txt"), delete("b.txt")]);
+        assert!(!tracker.waiting());
+        cleanup(folders);
+    }
+
+    #[test]
+    fn folder_moved_within_a_pair_is_one_rename() {
+        let folders = folders("folder", &["sync"]);
+        let root = &folders[0].local;
+        fs::create_dir_all(root.join("papers")).unwrap();
+        fs::write(root.join("papers/a.txt"), "a").unwrap();
+        let mut tracker = RenameTracker::new(folders);
+
+        tracker.convert(&rename(RenameMode::From, root.join("docs"), Some(5)));
+        let to = rename(RenameMode::To, root.join("papers"), Some(5));
+        assert_eq!(kinds(tracker.convert(&to)), [renamed("docs", "papers")]);
+
+        // The moved folder's own watch reports it leaving too, without a cookie
+        let own = rename(RenameMode::From, root.join("docs"), None);
+        assert!(tracker.convert(&own).is_empty());
+        assert!(!tracker.waiting());
+        cleanup(folders);
+    }
+
+    #[test]
+    fn folder_moved_in_brings_its_contents() {
+        let folders = folders("moved-in", &["sync"]);
+        let root = &folders[0].local;
+        fs::create_dir_all(root.join("photos/2024")).unwrap();
+        fs::write(root.join("photos/2024/a.jpg"), "a").unwrap();
+        fs::write(root.join("photos/b.jpg"), "b").unwrap();
+        let mut tracker = RenameTracker::new(folders);
+
+        let to = rename(RenameMode::To, root.join("photos"), Some(6));
+        assert_eq!(
+            kinds(tracker.convert(&to)),
+            [
+                SyncKind::Mkdir {
+                    path: "photos".to_string()
+                },
+                SyncKind::Mkdir {
+                    path: "photos/2024".to_string()
+                },
+                SyncKind::Create {
+                    path: "photos/2024/a.jpg".to_string()
+                },
+                SyncKind::Create {
+                    path: "photos/b.jpg".to_string()
+                },
+            ]
+        );
+        cleanup(folders);
+    }
+
+    #[test]
+    fn move_between_pairs_is_a_delete_and_a_create() {
+        let folders = folders("between", &["work", "home"]);
+        fs::write(folders[1].local.join("a.txt"), "a").unwrap();
+        let mut tracker = RenameTracker::new(folders);
+
+        tracker.convert(&rename(RenameMode::From, folders[0].local.join("a.txt"), Some(7)));
+        let to = rename(RenameMode::To, folders[1].local.join("a.txt"), Some(7));
+        let changes = tracker.convert(&to);
+
+        assert!(std::ptr::eq(changes[0].0, &folders[0]));
+        assert!(std::ptr::eq(changes[1].0, &folders[1]));
+        assert_eq!(
+            kinds(changes),
+            [
+                delete("a.txt"),
+                SyncKind::Create {
+                    path: "a.txt".to_string()
+                }
+            ]
+        );
+        cleanup(folders);
+    }
+
+    #[test]
+    fn change_at_a_waiting_path_sends_the_delete_first() {
+        let folders = folders("reuse", &["sync"]);
+        let root = &folders[0].local;
+        fs::write(root.join("a.txt"), "new").unwrap();
+        let mut tracker = RenameTracker::new(folders);
+
+        tracker.convert(&rename(RenameMode::From, root.join("a.txt"), Some(8)));
+        let create = Event::new(EventKind::Create(CreateKind::File)).add_path(root.join("a.txt"));
+        assert_eq!(
+            kinds(tracker.convert(&create)),
+            [
+                delete("a.txt"),
+                SyncKind::Create {
+                    path: "a.txt".to_string()
+                }
+            ]
+        );
+        cleanup(folders);
+    }
+}
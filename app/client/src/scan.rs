@@ -0,0 +1,129 @@
+use std::collections::{BTreeMap, HashMap};
+
+use common::{
+    Event, EventKind,
+    manifest::{FileEntry, Manifest},
+};
+
+use crate::{
+    folders::{FOLDERS, SyncFolder},
+    manifest::build_manifest,
+    write_to_changes,
+};
+
+/// Local files as of the last full scan, with inodes so renames can be told apart from a delete and
+/// a create
+pub fn load_index(folder: &SyncFolder) -> Option<Manifest> {
+    let content = std::fs::read_to_string(folder.staging_path("index.json")).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+pub fn save_index(folder: &SyncFolder, index: &Manifest) {
+    let _ = std::fs::create_dir_all(folder.staging_path(""));
+    index.save(&folder.staging_path("index.json"));
+}
+
+/// Scans every pair before the watcher starts and journals what changed while the client wasn't
+/// running
+pub async fn startup_scan() {
+    for folder in FOLDERS.iter() {
+        let previous = load_index(folder);
+        let cache = previous.clone().unwrap_or_default();
+        let rules = folder.ignore.read().unwrap().clone();
+        let root = folder.local.clone();
+        let Ok(index) =
+            tokio::task::spawn_blocking(move || build_manifest(&root, &cache, &rules)).await
+        else {
+            continue;
+        };
+
+        // Without an index there's nothing to compare with, the first reconcile syncs the folder
+        if let Some(previous) = previous {
+            let base = Manifest::load(&folder.manifest_path);
+            let events = offline_changes(&previous, &index, &base);
+            if !events.is_empty() {
+                println!("{} changes in {} while not running", events.len(), folder.name());
+            }
+            for event in events {
+                write_to_changes(folder, &event);
+            }
+        }
+
+        save_index(folder, &index);
+    }
+}
+
+/// Differences between the last and the current scan, leaving out what the server already has
+fn offline_changes(previous: &Manifest, current: &Manifest, base: &Manifest) -> Vec<Event> {
+    let on_server = |path: &str, entry: &FileEntry| {
+        base.files.get(path).is_some_and(|synced| synced.hash == entry.hash)
+    };
+
+    let gone: BTreeMap<&String, &FileEntry> = previous
+        .files
+        .iter()
+        .filter(|(path, _)| !current.files.contains_key(*path))
+        .collect();
+    let mut added: BTreeMap<&String, &FileEntry> = current
+        .files
+        .iter()
+        .filter(|(path, _)| !previous.files.contains_key(*path))
+        .collect();
+
+    // A new path with the same inode, or else the only one with the same content, was moved there
+    let by_inode: HashMap<u64, &String> = added
+        .iter()
+        .filter(|(_, entry)| entry.inode != 0)
+        .map(|(path, entry)| (entry.inode, *path))
+        .collect();
+    let mut by_hash: HashMap<&str, Vec<&String>> = HashMap::new();
+    for (path, entry) in &added {
+        by_hash.entry(entry.hash.as_str()).or_default().push(*path);
+    }
+
+    let mut events = Vec::new();
+    for (from, entry) in gone {
+        let moved_to = by_inode
+            .get(&entry.inode)
+            .filter(|_| entry.inode != 0)
+            .copied()
+            .filter(|to| added.get(*to).is_some_and(|e| e.hash == entry.hash))
+            .or_else(|| match by_hash.get(entry.hash.as_str()).map(Vec::as_slice) {
+                Some([to]) => Some(*to),
+                _ => None,
+            })
+            .filter(|to| added.contains_key(*to));
+
+        match moved_to {
+            Some(to) if base.files.contains_key(from) => {
+                added.remove(to);
+                events.push(Event::new(EventKind::Rename {
+                    from: from.clone(),
+                    to: to.clone(),
+                }));
+            }
+            _ if base.files.contains_key(from) => {
+                events.push(Event::new(EventKind::Delete { path: from.clone() }));
+            }
+            _ => (),
+        }
+    }
+
+    for (path, entry) in added {
+        if !on_server(path, entry) {
+            events.push(Event::new(EventKind::Create { path: path.clone() }));
+        }
+    }
+
+    for (path, entry) in &current.files {
+        let changed = previous
+            .files
+            .get(path)
+            .is_some_and(|old| old.hash != entry.hash);
+        if changed && !on_server(path, entry) {
+            events.push(Event::new(EventKind::Modify { path: path.clone() }));
+        }
+    }
+
+    events
+}
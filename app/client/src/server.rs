@@ -0,0 +1,130 @@
+use std::{fs, sync::Arc};
+
+use once_cell::sync::Lazy;
+use reqwest::Client;
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    crypto::ring,
+    pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+};
+use sha2::{Digest, Sha256};
+
+use crate::config::CONFIG;
+
+/// TLS settings for the sync server, shared by HTTP requests and the websocket
+pub static TLS: Lazy<Arc<ClientConfig>> = Lazy::new(|| Arc::new(tls_config()));
+
+/// Client for every request to the sync server, reusing connections between them
+pub static HTTP: Lazy<Client> = Lazy::new(|| {
+    Client::builder()
+        .use_preconfigured_tls(ClientConfig::clone(&TLS))
+        .build()
+        .expect("Couldn't build the HTTP client")
+});
+
+/// `path` on the sync server, e.g. `url("files/a.txt")`
+pub fn url(path: &str) -> String {
+    format!("{}/{}", CONFIG.server_url.trim_end_matches('/'), path)
+}
+
+/// The server's websocket, `wss` when the server is reached over HTTPS
+pub fn ws_url() -> String {
+    let base = CONFIG.server_url.trim_end_matches('/');
+    let base = match base.split_once("://") {
+        Some(("https", rest)) => format!("wss://{}", rest),
+        Some((_, rest)) => format!("ws://{}", rest),
+        None => format!("ws://{}", base),
+    };
+    format!("{}/ws", base)
+}
+
+fn tls_config() -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    if let Some(path) = &CONFIG.ca_cert {
+        let pem = fs::read(path).unwrap_or_else(|e| panic!("Couldn't read CA bundle {}: {}", path, e));
+        for cert in CertificateDer::pem_slice_iter(&pem) {
+            let cert = cert.unwrap_or_else(|e| panic!("Invalid CA bundle {}: {:?}", path, e));
+            roots
+                .add(cert)
+                .unwrap_or_else(|e| panic!("Invalid CA certificate in {}: {}", path, e));
+        }
+    }
+
+    let provider = Arc::new(ring::default_provider());
+    let roots = Arc::new(roots);
+    let mut config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("Default TLS versions are supported")
+        .with_root_certificates(roots.clone())
+        .with_no_client_auth();
+
+    if let Some(pin) = &CONFIG.pinned_cert {
+        let inner = WebPkiServerVerifier::builder_with_provider(roots, provider)
+            .build()
+            .expect("Root store is not empty");
+        let fingerprint = pin.replace(':', "").to_lowercase();
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedVerifier { inner, fingerprint }));
+    }
+
+    config
+}
+
+/// Checks the certificate as usual, then refuses it unless it's the pinned one
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    /// Lowercase hex SHA-256 of the server's certificate
+    fingerprint: String,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = format!("{:x}", Sha256::digest(end_entity.as_ref()));
+        if fingerprint != self.fingerprint {
+            return Err(rustls::Error::General(format!(
+                "Certificate {} doesn't match the pinned one",
+                fingerprint
+            )));
+        }
+
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
@@ -1,28 +1,35 @@
-use std::fs::File;
-use std::io::Write;
-
 use iced::{
     Theme,
     alignment::Horizontal,
-    widget::{Row, button, column, text}
+    widget::{Row, button, column, text, text_input}
 };
 use rfd::FileDialog;
+
+use crate::config;
+
 #[derive(Default)]
 pub struct SetUp {
     storage_path: String,
+    username: String,
+    password: String,
     dark_mode: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum Message {
     ToggleTheme,
     PickFolder,
+    UsernameChanged(String),
+    PasswordChanged(String),
+    Save,
 }
 
 impl SetUp {
     pub fn new() -> Self {
         Self {
             storage_path: "".to_string(),
+            username: "".to_string(),
+            password: "".to_string(),
             dark_mode: true,
         }
     }
@@ -36,7 +43,18 @@ impl SetUp {
         let message = text("Please choose which directory should be synced");
         let top_row = Row::new().padding(20).spacing(10).push(toggle);
 
-        column![top_row, message, path, pick_folder]
+        let username = text_input("Username", &self.username)
+            .on_input(Message::UsernameChanged)
+            .on_submit(Message::Save)
+            .width(220);
+        let password = text_input("Password", &self.password)
+            .on_input(Message::PasswordChanged)
+            .on_submit(Message::Save)
+            .secure(true)
+            .width(220);
+        let save = button(text("Save")).on_press(Message::Save);
+
+        column![top_row, message, path, pick_folder, username, password, save]
             .align_x(Horizontal::Center)
             .spacing(20)
             .into()
@@ -50,26 +68,28 @@ impl SetUp {
                 let folder = FileDialog::new().set_directory("/").pick_folder();
                 if let Some(folder) = folder {
                     self.storage_path = folder.to_string_lossy().to_string();
-                    let folder =
-                        format!("C:/Users/{}/AppData/Roaming/Drive_Sync", whoami::username());
-                    let file_path = format!("{}/config.toml", folder);
-                    let file = File::create(file_path.clone());
-                    let log_path = format!("{}/logs.toml", folder);
-                    let changes_path = format!("{}/changes.toml", folder);
-                    let _ = file.unwrap().write(
-                        format!(
-                            "storage_path=\"{}\"\nlog_path = \"{}\"\nchanges_path = \"{}\"",
-                            self.storage_path.replace("\\", "/"),
-                            log_path,
-                            changes_path
-                        )
-                        .as_bytes(),
-                    );
+                    self.save();
                 }
             }
+
+            Message::UsernameChanged(username) => self.username = username,
+
+            Message::PasswordChanged(password) => self.password = password,
+
+            Message::Save => self.save(),
         }
     }
 
+    /// Writes what has been entered to the config when a folder is picked or Save is pressed, setup
+    /// is done once the folder is set
+    fn save(&self) {
+        config::update_config(&[
+            ("storage_path", &self.storage_path.replace("\\", "/")),
+            ("username", &self.username),
+            ("password", &self.password),
+        ]);
+    }
+
     pub fn current_theme(&self) -> Theme {
         if self.dark_mode {
             Theme::Nord
@@ -86,7 +106,7 @@ pub fn run_setup() -> iced::Result {
     iced::application(SetUp::new, SetUp::update, SetUp::view)
         .title("Drive Sync")
         .window(iced::window::Settings {
-            size: iced::Size::new(300.0, 300.0),
+            size: iced::Size::new(300.0, 360.0),
             resizable: false,
             position: iced::window::Position::Centered,
             icon: Some(icon),
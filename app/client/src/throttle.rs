@@ -0,0 +1,121 @@
+use std::{
+    sync::{Mutex, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use chrono::{Local, NaiveTime};
+use futures_util::{Stream, StreamExt, stream};
+
+use crate::{config::CONFIG, progress::Direction, util::PAUSED};
+
+/// Pieces bodies are cut into, so a limited transfer is spread out instead of sent in bursts
+const PIECE_SIZE: usize = 64 * 1024;
+/// How often a paused transfer checks whether it can go on
+const PAUSE_POLL: Duration = Duration::from_millis(250);
+
+/// Bytes that can go out straight away, negative when transfers are ahead of the limit
+struct Bucket {
+    available: f64,
+    updated: Instant,
+    /// Bytes per second the bucket was last filled at, it starts over when the limit changes
+    rate: f64,
+}
+
+static UPLOAD: Mutex<Option<Bucket>> = Mutex::new(None);
+static DOWNLOAD: Mutex<Option<Bucket>> = Mutex::new(None);
+
+/// Bytes per second allowed right now, `None` for no limit
+fn limit(direction: Direction) -> Option<f64> {
+    let now = Local::now().time();
+    let scheduled = CONFIG.limit_schedule.iter().find_map(|window| {
+        let from = NaiveTime::parse_from_str(&window.from, "%H:%M").ok()?;
+        let to = NaiveTime::parse_from_str(&window.to, "%H:%M").ok()?;
+        let inside = if from <= to {
+            from <= now && now < to
+        } else {
+            now >= from || now < to
+        };
+        if !inside {
+            return None;
+        }
+        match direction {
+            Direction::Upload => window.upload_limit,
+            Direction::Download => window.download_limit,
+        }
+    });
+
+    let kib = scheduled.unwrap_or(match direction {
+        Direction::Upload => CONFIG.upload_limit,
+        Direction::Download => CONFIG.download_limit,
+    });
+    (kib > 0).then_some(kib as f64 * 1024.0)
+}
+
+/// Waits until `bytes` more can be transferred, holding the transfer for as long as syncing is
+/// paused
+pub async fn wait(direction: Direction, bytes: usize) {
+    while PAUSED.load(Ordering::Relaxed) {
+        tokio::time::sleep(PAUSE_POLL).await;
+    }
+
+    let Some(rate) = limit(direction) else {
+        return;
+    };
+
+    let delay = {
+        let bucket = match direction {
+            Direction::Upload => &UPLOAD,
+            Direction::Download => &DOWNLOAD,
+        };
+        let mut bucket = bucket.lock().unwrap();
+        let now = Instant::now();
+        let bucket = match bucket.as_mut() {
+            Some(b) if b.rate == rate => b,
+            _ => bucket.insert(Bucket {
+                available: rate,
+                updated: now,
+                rate,
+            }),
+        };
+
+        // At most a second's worth builds up while idle
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.available = (bucket.available + elapsed * rate).min(rate);
+        bucket.updated = now;
+        bucket.available -= bytes as f64;
+
+        (bucket.available < 0.0).then(|| Duration::from_secs_f64(-bucket.available / rate))
+    };
+
+    if let Some(delay) = delay {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Holds each chunk of an upload body back until the upload limit allows it
+pub fn limit_upload<S, B, E>(body: S) -> impl Stream<Item = Result<B, E>>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+{
+    body.then(|chunk| async move {
+        if let Ok(chunk) = &chunk {
+            wait(Direction::Upload, chunk.as_ref().len()).await;
+        }
+        chunk
+    })
+}
+
+/// An in-memory upload body, sent in pieces under the upload limit
+pub fn limited_body(data: Vec<u8>) -> reqwest::Body {
+    let data = Bytes::from(data);
+    let pieces = (0..data.len())
+        .step_by(PIECE_SIZE)
+        .map(move |start| {
+            let end = (start + PIECE_SIZE).min(data.len());
+            Ok::<_, std::io::Error>(data.slice(start..end))
+        })
+        .collect::<Vec<_>>();
+    reqwest::Body::wrap_stream(limit_upload(stream::iter(pieces)))
+}
@@ -0,0 +1,393 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use common::manifest::{hash_file, stat_entry};
+use reqwest::{Client, header};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::{
+    auth,
+    connection::{UploadResult, write_err_logs},
+    echo,
+    folders::SyncFolder,
+    progress::{self, Direction},
+    server::{self, HTTP},
+    throttle,
+};
+
+/// Hidden folder in the storage root holding partial transfers, never synced itself
+pub const STAGING_DIR: &str = ".drive_sync";
+/// Files above this size go through the chunked upload protocol
+pub const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+static PENDING_LOCK: Mutex<()> = Mutex::new(());
+
+/// An upload the server has part of, resumable as long as the local file hasn't changed since
+#[derive(Clone, Serialize, Deserialize)]
+struct PendingUpload {
+    id: String,
+    size: u64,
+    mtime: i64,
+}
+
+fn update_pending<T>(
+    folder: &SyncFolder,
+    f: impl FnOnce(&mut HashMap<String, PendingUpload>) -> T,
+) -> T {
+    let _guard = PENDING_LOCK.lock().unwrap();
+    let path = folder.staging_path("uploads.json");
+
+    let mut pending: HashMap<String, PendingUpload> = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    let result = f(&mut pending);
+
+    let _ = std::fs::create_dir_all(folder.staging_path(""));
+    if let Ok(content) = serde_json::to_string(&pending) {
+        let _ = std::fs::write(&path, content);
+    }
+
+    result
+}
+
+/// Offset the server has received for `id`, `None` if it no longer knows the upload
+async fn upload_offset(client: &Client, id: &str) -> Option<u64> {
+    let res = auth::send(client.get(server::url(&format!("uploads/{}", id))))
+        .await
+        .ok()?;
+
+    if res.status() != 200 {
+        return None;
+    }
+
+    res.json::<Value>().await.ok()?.get("offset")?.as_u64()
+}
+
+/// Uploads `local` to `path` in chunks, picking up where a previous attempt left off.
+pub async fn send_file_chunked(
+    folder: &SyncFolder,
+    path: &str,
+    local: &Path,
+    base_hash: Option<String>,
+) -> UploadResult {
+    let Ok(meta) = fs::metadata(local).await else {
+        return UploadResult::Failed;
+    };
+    let size = meta.len();
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0);
+
+    let client = HTTP.clone();
+    let pending = update_pending(folder, |pending| pending.get(path).cloned())
+        .filter(|p| p.size == size && p.mtime == mtime);
+
+    let resumed = match pending {
+        Some(p) => upload_offset(&client, &p.id).await.map(|offset| (p.id, offset)),
+        None => None,
+    };
+
+    let (id, mut offset) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let local_path = local.to_path_buf();
+            let hash = tokio::task::spawn_blocking(move || hash_file(&local_path).ok())
+                .await
+                .unwrap_or(None);
+
+            let res = auth::send(
+                client
+                    .post(server::url("uploads"))
+                    .json(&json!({
+                        "path": folder.remote_path(path),
+                        "size": size,
+                        "hash": hash,
+                        "base_hash": base_hash,
+                    })),
+            )
+            .await;
+
+            let id = match res {
+                Ok(res) if res.status() == 200 => res
+                    .json::<Value>()
+                    .await
+                    .ok()
+                    .and_then(|v| v.get("id")?.as_str().map(|id| id.to_string())),
+                _ => None,
+            };
+
+            let Some(id) = id else {
+                write_err_logs(Some(path), None, "Starting upload").await;
+                return UploadResult::Failed;
+            };
+
+            update_pending(folder, |pending| {
+                pending.insert(
+                    path.to_string(),
+                    PendingUpload {
+                        id: id.clone(),
+                        size,
+                        mtime,
+                    },
+                )
+            });
+            (id, 0)
+        }
+    };
+
+    let Ok(mut file) = fs::File::open(local).await else {
+        return UploadResult::Failed;
+    };
+    let mut buffer = vec![0u8; CHUNK_SIZE as usize];
+    let key = folder.remote_path(path);
+    progress::advance(&key, offset);
+
+    while offset < size {
+        if file.seek(std::io::SeekFrom::Start(offset)).await.is_err() {
+            return UploadResult::Failed;
+        }
+
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match file.read(&mut buffer[filled..]).await {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(_) => return UploadResult::Failed,
+            }
+        }
+
+        if filled == 0 {
+            write_err_logs(Some(path), Some("File shrank during upload"), "Sending chunk").await;
+            return UploadResult::Failed;
+        }
+
+        let res = auth::send(
+            client
+                .put(server::url(&format!(
+                    "uploads/{}?offset={}",
+                    id, offset
+                )))
+                .body(throttle::limited_body(buffer[..filled].to_vec())),
+        )
+        .await;
+
+        // 416 means the server is at a different offset than we are, continue from there
+        let next = match res {
+            Ok(res) if res.status() == 200 || res.status() == 416 => res
+                .json::<Value>()
+                .await
+                .ok()
+                .and_then(|v| v.get("offset")?.as_u64()),
+            Ok(res) => {
+                write_err_logs(Some(path), Some(res.status().as_str()), "Sending chunk").await;
+                None
+            }
+            Err(_) => {
+                write_err_logs(Some(path), None, "Sending chunk").await;
+                None
+            }
+        };
+
+        let Some(next) = next else {
+            return UploadResult::Failed;
+        };
+        progress::advance(&key, next.saturating_sub(offset));
+        offset = next;
+    }
+
+    let res = auth::send(client.post(server::url(&format!("uploads/{}/commit", id)))).await;
+
+    let status = match res {
+        Ok(res) => res.status(),
+        Err(_) => {
+            write_err_logs(Some(path), None, "Committing upload").await;
+            return UploadResult::Failed;
+        }
+    };
+
+    // Anything but a transient failure means the server dropped the upload
+    if status != 500 {
+        update_pending(folder, |pending| pending.remove(path));
+    }
+
+    if status == 409 {
+        return UploadResult::Conflict;
+    }
+    if status != 200 {
+        write_err_logs(Some(path), Some(status.as_str()), "Committing upload").await;
+        return UploadResult::Failed;
+    }
+
+    UploadResult::Done
+}
+
+/// Downloads `path` into `dest` through a partial file in the staging folder, resuming a previous
+/// attempt with a range request as long as the server copy is still the one it started from. The
+/// partial is checked against the server's hash and flushed to disk before it replaces `dest`.
+pub async fn download_file(folder: &SyncFolder, path: &str, dest: &Path) -> bool {
+    let key = format!("{:x}", Sha256::digest(path.as_bytes()));
+    let part = folder.staging_path(&format!("{}.part", key));
+    let etag_path = folder.staging_path(&format!("{}.etag", key));
+
+    let offset = fs::metadata(&part).await.map(|m| m.len()).unwrap_or(0);
+    let etag = fs::read_to_string(&etag_path).await.ok();
+
+    let url = server::url(&format!("files/{}", folder.remote_path(path)));
+    let mut req = HTTP.get(url);
+    if offset > 0
+        && let Some(etag) = &etag
+    {
+        req = req
+            .header(header::RANGE, format!("bytes={}-", offset))
+            .header(header::IF_RANGE, etag);
+    }
+
+    let Ok(mut res) = auth::send(req).await else {
+        write_err_logs(Some(path), None, "Getting file").await;
+        return false;
+    };
+
+    let status = res.status();
+    if status == 416 {
+        // Our partial no longer lines up with the server copy, start over next time
+        let _ = fs::remove_file(&part).await;
+        let _ = fs::remove_file(&etag_path).await;
+        return false;
+    }
+    if status != 200 && status != 206 {
+        write_err_logs(Some(path), Some(status.as_str()), "Getting file").await;
+        return false;
+    }
+
+    if fs::create_dir_all(folder.staging_path("")).await.is_err() {
+        return false;
+    }
+
+    // Of the whole file, whether or not this is only the rest of it
+    let expected_hash = res
+        .headers()
+        .get("X-Content-Hash")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let key = folder.remote_path(path);
+    let resumed = if status == 206 { offset } else { 0 };
+    if let Some(length) = res.content_length() {
+        progress::resize(&key, resumed + length);
+    }
+    progress::advance(&key, resumed);
+
+    let file = if status == 206 {
+        fs::OpenOptions::new().append(true).open(&part).await
+    } else {
+        match res.headers().get(header::ETAG).and_then(|v| v.to_str().ok()) {
+            Some(etag) => {
+                let _ = fs::write(&etag_path, etag).await;
+            }
+            None => {
+                let _ = fs::remove_file(&etag_path).await;
+            }
+        }
+        fs::File::create(&part).await
+    };
+    let Ok(mut file) = file else {
+        return false;
+    };
+
+    loop {
+        match res.chunk().await {
+            Ok(Some(chunk)) => {
+                if file.write_all(&chunk).await.is_err() {
+                    return false;
+                }
+                progress::advance(&key, chunk.len() as u64);
+                throttle::wait(Direction::Download, chunk.len()).await;
+            }
+            Ok(None) => break,
+            Err(_) => {
+                // Keep what arrived so the next attempt can resume
+                let _ = file.flush().await;
+                write_err_logs(Some(path), None, "Download interrupted").await;
+                return false;
+            }
+        }
+    }
+
+    if file.flush().await.is_err() || file.sync_all().await.is_err() {
+        return false;
+    }
+    drop(file);
+
+    let part_path = part.clone();
+    let Ok(Ok(hash)) = tokio::task::spawn_blocking(move || hash_file(&part_path)).await else {
+        return false;
+    };
+    if expected_hash.is_some_and(|expected| expected != hash) {
+        // Resuming won't fix a corrupt partial, the next attempt starts over
+        let _ = fs::remove_file(&part).await;
+        let _ = fs::remove_file(&etag_path).await;
+        write_err_logs(Some(path), None, "Hash mismatch").await;
+        return false;
+    }
+
+    if let Some(dir) = dest.parent() {
+        echo::expect_folder(dir);
+    }
+    if let Some(dir) = dest.parent()
+        && let Err(e) = fs::create_dir_all(dir).await
+    {
+        write_err_logs(
+            Some(path),
+            Some(format!("Error creating dir{}", e).as_str()),
+            "Getting file",
+        )
+        .await;
+        return false;
+    }
+
+    if let Some(entry) = stat_entry(&part, hash) {
+        echo::expect_file(dest, entry);
+    }
+    if let Err(e) = fs::rename(&part, dest).await {
+        write_err_logs(
+            Some(path),
+            Some(format!("Error writing to file{}", e).as_str()),
+            "Getting file",
+        )
+        .await;
+        return false;
+    }
+
+    sync_dir(dest).await;
+
+    let _ = fs::remove_file(&etag_path).await;
+    true
+}
+
+/// Flushes the rename of `path` to disk, so a crash can't leave the folder pointing at nothing
+#[cfg(unix)]
+async fn sync_dir(path: &Path) {
+    if let Some(dir) = path.parent()
+        && let Ok(dir) = fs::File::open(dir).await
+    {
+        let _ = dir.sync_all().await;
+    }
+}
+
+/// Directories can't be opened for syncing here, the rename is as durable as the platform makes it
+#[cfg(not(unix))]
+async fn sync_dir(_path: &Path) {}
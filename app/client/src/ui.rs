@@ -1,13 +1,25 @@
 use iced::{
-    Border, Color, Length,
+    Border, Color, Length, Subscription,
     alignment::{Horizontal, Vertical},
-    widget::{button, column, container, progress_bar, row, text},
+    futures::{SinkExt, Stream},
+    widget::{Column, button, column, container, progress_bar, row, scrollable, text},
 };
 use iced_aw::Spinner;
-use std::{net::TcpStream, sync::{Arc, Mutex}};
-use tungstenite::{WebSocket, connect, stream::MaybeTlsStream};
+use std::{
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
 use rfd::FileDialog;
 
+use crate::{
+    activity::{self, Activity, Outcome},
+    conflict::{CONFLICTS, Conflict},
+    connection::Socket,
+    folders::{FOLDERS, FolderStatus},
+    progress::{Direction, PROGRESS, Progress, TransferState, format_bytes},
+    util::{IS_ANALYSING_LOGS, PAUSED, set_paused},
+};
+
 // ── Palette ────────────────────────────────────────────────────────────────────
 
 const SURFACE:  Color = Color { r: 0.094, g: 0.094, b: 0.110, a: 1.0 };
@@ -43,26 +55,43 @@ fn hfill() -> iced::widget::Space {
 
 // ── State ──────────────────────────────────────────────────────────────────────
 
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Page {
+    #[default]
+    Main,
+    Activity,
+}
+
 #[derive(Default)]
 pub struct Counter {
     status: bool,
     connecting: bool,
     storage_path: String,
     dark_mode: bool,
-    sync_progress: f32,
-    socket: Arc<Mutex<Option<WebSocket<MaybeTlsStream<TcpStream>>>>>,
+    progress: Progress,
+    conflicts: Vec<Conflict>,
+    folders: Vec<(String, FolderStatus)>,
+    page: Page,
+    activity: Vec<Activity>,
+    socket: Arc<Socket>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum Message {
     ToggleTheme,
     Connect,
     PickFolder,
+    Tick,
+    DismissConflicts,
+    Progress(Progress),
+    ShowPage(Page),
+    TogglePause,
+    Retry(u64),
 }
 
 impl Counter {
-    pub fn new(sock: Arc<Mutex<Option<WebSocket<MaybeTlsStream<TcpStream>>>>>) -> Self {
-        let connected = sock.lock().unwrap().is_some();
+    pub fn new(sock: Arc<Socket>) -> Self {
+        let connected = sock.is_connected();
         Self {
             socket: sock,
             status: connected,
@@ -72,20 +101,20 @@ impl Counter {
     }
 
     pub fn view(&self) -> iced::Element<'_, Message> {
-        let content = column![
-            container(
-                column![
-                    self.connection_section(),
-                    vspace(12.0),
-                    self.folder_section(),
-                    vspace(12.0),
-                    self.progress_section(),
-                    vspace(12.0),
-                ]
-            )
-            .padding([0u16, 16]),
-            self.footer(),
-        ];
+        let page = match self.page {
+            Page::Main => column![
+                self.connection_section(),
+                vspace(12.0),
+                self.folder_section(),
+                vspace(12.0),
+                self.pairs_section(),
+                self.progress_section(),
+                vspace(12.0),
+                self.conflicts_section(),
+            ],
+            Page::Activity => column![self.activity_section(), vspace(12.0)],
+        };
+        let content = column![container(page).padding([0u16, 16]), self.footer()];
 
         container(content)
             .style(|_| iced::widget::container::Style {
@@ -222,51 +251,309 @@ impl Counter {
         .into()
     }
 
+    fn pairs_section(&self) -> iced::Element<'_, Message> {
+        if self.folders.is_empty() {
+            return vspace(0.0);
+        }
+
+        let entries = self.folders.iter().fold(Column::new().spacing(8), |col, (name, status)| {
+            let (state, color) = match (&status.syncing, &status.last_synced) {
+                (true, _) => ("Syncing…".to_string(), ACCENT),
+                (false, Some(time)) => (format!("Synced {}", time), SUCCESS),
+                (false, None) => ("Never synced".to_string(), TEXT_DIM),
+            };
+            col.push(
+                column![
+                    text(name).size(12).color(TEXT),
+                    text(state).size(10).color(color),
+                ]
+                .spacing(2),
+            )
+        });
+
+        column![
+            Self::section_label("synced folders"),
+            vspace(6.0),
+            card(entries).width(Length::Fill),
+            vspace(12.0),
+        ]
+        .spacing(0)
+        .into()
+    }
+
     fn progress_section(&self) -> iced::Element<'_, Message> {
-        let pct = format!("{:.0}%", self.sync_progress * 100.0);
+        let progress = &self.progress;
+        // Nothing moved yet this session, full once a folder has finished a sync
+        let fraction = if progress.files_total() > 0 {
+            progress.fraction()
+        } else if self.folders.iter().any(|(_, status)| status.last_synced.is_some()) {
+            1.0
+        } else {
+            0.0
+        };
 
+        let files = format!(
+            "{} OF {} FILES",
+            progress.files_done(),
+            progress.files_total()
+        );
+        let paused = PAUSED.load(Ordering::Relaxed);
+        let bar_color = if paused { TEXT_SUB } else { ACCENT };
         let header = row![
-            text("FILES SYNCED").size(9).color(TEXT_DIM),
+            text(files).size(9).color(TEXT_DIM),
             hfill(),
-            text(pct).size(11).color(ACCENT),
+            button(text(if paused { "Resume" } else { "Pause" }).size(11).color(TEXT_SUB))
+                .on_press(Message::TogglePause)
+                .padding([2u16, 8])
+                .style(|_, _| button::Style {
+                    background: None,
+                    text_color: TEXT_SUB,
+                    ..Default::default()
+                }),
+            text(format!("{:.0}%", fraction * 100.0)).size(11).color(bar_color),
         ]
+        .align_y(Vertical::Center)
         .width(Length::Fill);
 
         let bar = container(
-            progress_bar(0.0..=1.0, self.sync_progress)
-                .style(|_| iced::widget::progress_bar::Style {
+            progress_bar(0.0..=1.0, fraction)
+                .style(move |_| iced::widget::progress_bar::Style {
                     background: SURFACE2.into(),
-                    bar: ACCENT.into(),
+                    bar: bar_color.into(),
                     border: Border { radius: 2.0.into(), ..Default::default() },
                 })
         )
         .height(Length::Fixed(4.0));
 
-        column![
+        let mut section = column![
             Self::section_label("sync progress"),
             vspace(6.0),
             header,
             vspace(8.0),
             bar,
         ]
+        .spacing(0);
+
+        if let Some(current) = progress.current() {
+            let mut rate = format!("{}/s", format_bytes(progress.throughput()));
+            if let Some(eta) = progress.eta() {
+                rate = format!("{} · {} left", rate, format_duration(eta));
+            }
+            section = section
+                .push(vspace(8.0))
+                .push(
+                    row![
+                        text(format!("{} {}", arrow(current.direction), current.path))
+                            .size(11)
+                            .color(TEXT_SUB),
+                        hfill(),
+                        text(rate).size(10).color(TEXT_DIM),
+                    ]
+                    .align_y(Vertical::Center)
+                );
+        }
+
+        // Latest few transfers of the cycle, newest first
+        let transfers = progress.transfers.iter().rev().take(3).fold(
+            Column::new().spacing(4),
+            |list, transfer| {
+                let (state, color) = match transfer.state {
+                    TransferState::Queued => ("queued".to_string(), TEXT_DIM),
+                    TransferState::Active if transfer.size > 0 => (
+                        format!("{:.0}%", transfer.done as f32 / transfer.size as f32 * 100.0),
+                        ACCENT,
+                    ),
+                    TransferState::Active => (format_bytes(transfer.done as f64), ACCENT),
+                    TransferState::Done => ("done".to_string(), SUCCESS),
+                    TransferState::Failed => ("failed".to_string(), DANGER),
+                };
+                list.push(
+                    row![
+                        text(format!("{} {}", arrow(transfer.direction), transfer.path))
+                            .size(10)
+                            .color(TEXT_SUB),
+                        hfill(),
+                        text(state).size(10).color(color),
+                    ]
+                    .align_y(Vertical::Center)
+                )
+            },
+        );
+        if !progress.transfers.is_empty() {
+            section = section.push(vspace(8.0)).push(transfers);
+        }
+
+        section.into()
+    }
+
+    fn conflicts_section(&self) -> iced::Element<'_, Message> {
+        if self.conflicts.is_empty() {
+            return vspace(0.0);
+        }
+
+        let header = row![
+            text(format!("{} KEPT BOTH COPIES", self.conflicts.len())).size(9).color(DANGER),
+            hfill(),
+            button(text("Dismiss").size(11).color(TEXT_SUB))
+                .on_press(Message::DismissConflicts)
+                .padding([2u16, 8])
+                .style(|_, _| button::Style {
+                    background: None,
+                    text_color: TEXT_SUB,
+                    ..Default::default()
+                }),
+        ]
+        .align_y(Vertical::Center)
+        .width(Length::Fill);
+
+        // Only the latest few fit in the window
+        let entries = self.conflicts.iter().rev().take(3).fold(
+            Column::new().spacing(6),
+            |col, conflict| {
+                col.push(
+                    column![
+                        text(&conflict.path).size(12).color(TEXT),
+                        text(format!("your edits → {}", conflict.copy)).size(10).color(TEXT_DIM),
+                    ]
+                    .spacing(2),
+                )
+            },
+        );
+
+        column![
+            Self::section_label("conflicts"),
+            vspace(6.0),
+            card(column![header, vspace(8.0), entries]).width(Length::Fill),
+            vspace(12.0),
+        ]
         .spacing(0)
         .into()
     }
 
+    fn activity_section(&self) -> iced::Element<'_, Message> {
+        if self.activity.is_empty() {
+            return column![
+                Self::section_label("activity"),
+                vspace(6.0),
+                card(text("Nothing synced yet").size(12).color(TEXT_SUB)).width(Length::Fill),
+            ]
+            .into();
+        }
+
+        let entries = self.activity.iter().fold(Column::new().spacing(6), |col, entry| {
+            col.push(card(Self::activity_entry(entry)).padding(10).width(Length::Fill))
+        });
+
+        column![
+            Self::section_label("activity"),
+            vspace(6.0),
+            scrollable(entries).height(Length::Fixed(430.0)),
+        ]
+        .spacing(0)
+        .into()
+    }
+
+    fn activity_entry(entry: &Activity) -> iced::Element<'_, Message> {
+        let title = match &entry.path {
+            Some(path) => format!("{} {}", entry.operation, path),
+            None => entry.operation.clone(),
+        };
+
+        let (state, color) = match entry.outcome {
+            Outcome::Synced => ("synced".to_string(), SUCCESS),
+            Outcome::Failed => {
+                let mut state = "failed".to_string();
+                if let Some(detail) = &entry.detail {
+                    state = format!("{} · {}", state, detail.to_lowercase());
+                }
+                if let Some(response) = &entry.response {
+                    state = format!("{} · {}", state, response);
+                }
+                (state, DANGER)
+            }
+        };
+
+        let mut details = row![
+            column![
+                text(title).size(11).color(TEXT),
+                text(format!("{} — {}", entry.time, state)).size(10).color(color),
+            ]
+            .spacing(2)
+            .width(Length::Fill),
+        ]
+        .spacing(8)
+        .align_y(Vertical::Center);
+
+        if entry.outcome == Outcome::Failed && entry.retry.is_some() {
+            details = details.push(
+                button(text("Retry").size(10).color(ACCENT))
+                    .on_press(Message::Retry(entry.id))
+                    .padding([4u16, 10])
+                    .style(|_, _| button::Style {
+                        background: Some(Color { a: 0.08, ..ACCENT }.into()),
+                        border: Border { color: ACCENT_D, width: 1.0, radius: 8.0.into() },
+                        text_color: ACCENT,
+                        ..Default::default()
+                    }),
+            );
+        }
+
+        details.into()
+    }
+
     fn footer(&self) -> iced::Element<'_, Message> {
+        let (status, dot_color) = if PAUSED.load(Ordering::Relaxed) {
+            ("paused".to_string(), TEXT_SUB)
+        } else if self.progress.is_busy() {
+            (
+                format!(
+                    "syncing {} of {}",
+                    self.progress.files_done() + 1,
+                    self.progress.files_total()
+                ),
+                ACCENT,
+            )
+        } else if IS_ANALYSING_LOGS.load(Ordering::Relaxed) {
+            ("checking for changes".to_string(), ACCENT)
+        } else {
+            ("idle".to_string(), SUCCESS)
+        };
+
         let dot = container(iced::widget::Space::new())
             .width(Length::Fixed(5.0))
             .height(Length::Fixed(5.0))
-            .style(|_| iced::widget::container::Style {
-                background: Some(SUCCESS.into()),
+            .style(move |_| iced::widget::container::Style {
+                background: Some(dot_color.into()),
                 border: Border { radius: 3.0.into(), ..Default::default() },
                 ..Default::default()
             });
 
+        let (page_label, page) = match self.page {
+            Page::Main => {
+                let failures = self.activity.iter().filter(|e| e.outcome == Outcome::Failed).count();
+                let label = if failures > 0 {
+                    format!("activity ({} failed)", failures)
+                } else {
+                    "activity".to_string()
+                };
+                (label, Page::Activity)
+            }
+            Page::Activity => ("back".to_string(), Page::Main),
+        };
+        let page_btn = button(text(page_label).size(10).color(TEXT_SUB))
+            .on_press(Message::ShowPage(page))
+            .padding(0)
+            .style(|_, _| button::Style { text_color: TEXT_SUB, ..Default::default() });
+
         container(
-            row![dot, text("v1.0.0 — idle").size(10).color(TEXT_DIM)]
-                .spacing(6)
-                .align_y(Vertical::Center)
+            row![
+                dot,
+                text(format!("v1.0.0 — {}", status)).size(10).color(TEXT_DIM),
+                hfill(),
+                page_btn,
+            ]
+            .spacing(6)
+            .align_y(Vertical::Center)
         )
         .padding([10u16, 16])
         .width(Length::Fill)
@@ -277,25 +564,50 @@ impl Counter {
         .into()
     }
 
+    pub fn subscription(&self) -> Subscription<Message> {
+        Subscription::batch([
+            iced::time::every(Duration::from_secs(1)).map(|_| Message::Tick),
+            Subscription::run(progress_updates),
+        ])
+    }
+
     pub fn update(&mut self, message: Message) {
         match message {
             Message::ToggleTheme => self.dark_mode = !self.dark_mode,
 
+            Message::Tick => {
+                self.conflicts = CONFLICTS.lock().unwrap().clone();
+                self.folders = FOLDERS
+                    .iter()
+                    .map(|folder| (folder.name(), folder.status.lock().unwrap().clone()))
+                    .collect();
+                self.activity = activity::recent();
+                self.status = self.socket.is_connected();
+                self.connecting = self.socket.is_enabled() && !self.status;
+            }
+
+            Message::Progress(progress) => self.progress = progress,
+
+            Message::ShowPage(page) => self.page = page,
+
+            Message::TogglePause => set_paused(!PAUSED.load(Ordering::Relaxed)),
+
+            Message::Retry(id) => {
+                activity::retry(id);
+                self.activity = activity::recent();
+            }
+
+            Message::DismissConflicts => {
+                CONFLICTS.lock().unwrap().clear();
+                self.conflicts.clear();
+            }
+
+            // The socket thread does the actual connecting and reconnecting
             Message::Connect => {
-                let mut sock = self.socket.lock().unwrap();
-                if self.status {
-                    if let Some(mut ws) = sock.take() {
-                        let _ = ws.close(None);
-                    }
-                    self.status = false;
-                    return;
-                }
-                self.connecting = true;
-                if let Ok((ws, _)) = connect("ws://localhost:3000/ws") {
-                    *sock = Some(ws);
-                    self.status = true;
-                }
-                self.connecting = false;
+                let enable = !self.socket.is_enabled();
+                self.socket.set_enabled(enable);
+                self.connecting = enable;
+                self.status = false;
             }
 
             Message::PickFolder => {
@@ -306,4 +618,36 @@ impl Counter {
         }
     }
 
-}
\ No newline at end of file
+}
+
+/// Progress published by the sync engine, at most ten updates a second
+fn progress_updates() -> impl Stream<Item = Message> {
+    iced::stream::channel(1, async |mut output| {
+        let mut updates = PROGRESS.subscribe();
+        while updates.changed().await.is_ok() {
+            let progress = updates.borrow_and_update().clone();
+            if output.send(Message::Progress(progress)).await.is_err() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+}
+
+fn arrow(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Upload => "↑",
+        Direction::Download => "↓",
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 3600 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {}m", secs / 3600, secs % 3600 / 60)
+    }
+}
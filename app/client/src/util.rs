@@ -1,319 +1,482 @@
-use core::fmt;
 use std::{
-    collections::{HashMap, VecDeque},
     fs::{self, OpenOptions},
     io::Write,
-    sync::{Arc, atomic::{AtomicBool, Ordering}},
+    path::Path,
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    time::SystemTime,
 };
 
+use chrono::Local;
+use common::{
+    Event, EventKind, compact,
+    manifest::{Manifest, file_entry, stat_entry},
+    parse_lines, to_line,
+};
+use futures_util::{StreamExt, stream};
 use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
-use tokio::{sync::Mutex};
+use sha2::{Digest, Sha256};
+use tokio::sync::Notify;
 
 use crate::{
-    config::CONFIG,
-    connection::{edit_client_side, edit_server_side, fetch_logs, send_logs},
+    activity::{self, Retry},
+    config::{self, CONFIG},
+    conflict::resolve_conflict,
+    connection::{
+        ChangesResult, UploadResult, delete_file, fetch_changes, fetch_manifest, get_file,
+        send_delete_file, send_file, send_logs,
+    },
+    echo,
+    folders::{FOLDERS, SyncFolder},
+    manifest::{Action, build_manifest, plan},
+    progress::{self, Direction},
+    queue::{self, QueuedRetry, Sent},
+    scan::{load_index, save_index},
+    server::{self, HTTP},
     write_to_changes,
 };
 
 pub static IS_ANALYSING_LOGS: Lazy<Arc<AtomicBool>> =
     Lazy::new(|| Arc::new(AtomicBool::new(false)));
 
-pub static IN_MEMORY_EVENTS: Lazy<Arc<Mutex<Vec<notify::Event>>>> =
-    Lazy::new(|| Arc::new(Mutex::new(Vec::new())));
-
-#[derive(Clone, Serialize, Deserialize)]
-pub struct Event {
-    pub event_type: String,
-    pub path: String,
-    pub time: i64,
-}
-
-impl fmt::Display for Event {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Type : {} Path : \"{}\" Time : {} ",
-            self.event_type, self.path, self.time
-        )
+/// Set from the UI or the control API, the watcher keeps journalling but nothing is synced and
+/// transfers under way are held until resumed
+pub static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Wakes the sync loop early, when the server pushes changes from another device
+pub static SYNC_NOW: Notify = Notify::const_new();
+
+/// Identifies this install to the server, so its own changes can be skipped in the change feed.
+/// Kept in the state folder so it survives restarts.
+pub static DEVICE_ID: Lazy<String> = Lazy::new(|| {
+    let path = config::state_dir().join("device_id");
+    if let Ok(id) = fs::read_to_string(&path)
+        && !id.trim().is_empty()
+    {
+        return id.trim().to_string();
     }
-}
-
-impl Event {
-    fn print(&self) -> String {
-        let path = &self.path;
-
-        let event_type = self.event_type.replace("(Any)", "");
 
-        return format!(
-            "[[events]]\nevent_type = {:?}\npath = \"{}\"\ntime = {:?}\n",
-            event_type, path, self.time
-        );
+    let seed = format!(
+        "{}{:?}{}",
+        whoami::devicename(),
+        SystemTime::now(),
+        std::process::id()
+    );
+    let id = format!("{:x}", Sha256::digest(seed.as_bytes()))[..16].to_string();
+    let _ = fs::create_dir_all(config::state_dir());
+    let _ = fs::write(&path, &id);
+    id
+});
+
+/// Resuming syncs straight away, to catch up on what changed while paused
+pub fn set_paused(paused: bool) {
+    PAUSED.store(paused, Ordering::Relaxed);
+    if !paused {
+        SYNC_NOW.notify_one();
     }
 }
 
-impl PartialEq for Event {
-    fn eq(&self, other: &Self) -> bool {
-        self.path == other.path && self.event_type == other.event_type && self.time == other.time
+pub async fn analyse_logs() {
+    if PAUSED.load(Ordering::Relaxed) {
+        return;
     }
-}
 
-#[derive(Serialize, Deserialize)]
-pub struct Events {
-    events: Vec<Event>,
-}
-
-pub async fn analyse_logs() {
-    let req = reqwest::get("http://localhost:3000/health").await;
+    let req = HTTP.get(server::url("health")).send().await;
     if req.is_ok() {
         IS_ANALYSING_LOGS.store(true, Ordering::Relaxed);
-        process_changes();
-        clean_logs(false);
-        process_logs().await;
-
-        let _ = fs::write(&CONFIG.changes_path, "");
-
-        let mut locked = IN_MEMORY_EVENTS.lock().await;
-        for e in locked.drain(..) {
-            write_to_changes( e);
+        progress::new_cycle();
+        for folder in FOLDERS.iter() {
+            sync_folder(folder).await;
         }
-
-        clean_logs(true);
+        progress::end_cycle();
         IS_ANALYSING_LOGS.store(false, Ordering::Relaxed);
     }
 }
 
-pub fn process_changes() {
-    let content = fs::read_to_string(&CONFIG.changes_path).unwrap_or_default();
-    let events = toml::from_str(&content);
-
-    if events.is_ok() {
-        let events: Events = events.unwrap();
-        let mut queue: VecDeque<Event> = VecDeque::new();
-        let mut cleaned_queue: VecDeque<Event> = VecDeque::new();
-
-        for event in events.events {
-            queue.push_back(event);
-        }
-
-        let mut prev_event: Option<Event> = None;
-
-        for event in queue {
-            if prev_event.is_some() {
-                // TODO on linux a remove event contains a single rename event (from) if there is no following to, it is a remove
-                if event.event_type.contains("To") {
-                    let prev = prev_event.clone().unwrap();
-                    let new_event = Event {
-                        event_type: "Rename".to_string(),
-                        path: format!("{}$-${}", prev.path.to_string(), &event.path),
-                        time: event.time,
-                    };
-                    cleaned_queue.push_back(new_event);
-                }
-            }
+/// One sync cycle for a folder pair: push local changes, then pull the server's
+async fn sync_folder(folder: &SyncFolder) {
+    folder.status.lock().unwrap().syncing = true;
 
-            if event.event_type.contains("Remove") || event.event_type.contains("Modify(Any)") {
-                cleaned_queue.push_back(event.clone());
+    for retry in activity::take_retries(folder) {
+        match retry {
+            Retry::Send(event) => {
+                queue::revive(folder, &event);
+                write_to_changes(folder, &event);
             }
-
-            prev_event = Some(event);
+            Retry::Reconcile => folder.reconciled.store(false, Ordering::Relaxed),
         }
+    }
 
-        let mut message = "".to_string();
+    clean_logs(folder, false);
+    process_logs(folder).await;
+    pull_changes(folder).await;
 
-        for event in cleaned_queue {
-            message = format!("{}{}", message, event.print());
-        }
+    let _ = fs::write(&folder.changes_path, "");
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(&CONFIG.changes_path)
-            .unwrap();
-        let _ = file.write(message.as_bytes());
+    let mut locked = folder.pending.lock().await;
+    for e in locked.drain(..) {
+        write_to_changes(folder, &e);
     }
+    drop(locked);
+
+    clean_logs(folder, true);
+
+    let mut status = folder.status.lock().unwrap();
+    status.syncing = false;
+    status.last_synced = Some(Local::now().format("%H:%M").to_string());
 }
 
-pub fn clean_logs(is_logs: bool) {
+/// Rewrites the changes or logs file with only the events that still matter
+pub fn clean_logs(folder: &SyncFolder, is_logs: bool) {
     let path = if is_logs {
-        &CONFIG.log_path
+        &folder.log_path
     } else {
-        &CONFIG.changes_path
+        &folder.changes_path
     };
 
-    let events = toml::from_str(&fs::read_to_string(path).unwrap());
+    let events = compact(get_events(path));
+    println!("All events:");
+    for event in &events {
+        println!("{}", event);
+    }
 
-    if events.is_ok() {
-        let _ = fs::write(path, "");
+    let content: String = events.iter().map(to_line).collect();
+    let _ = fs::write(path, content);
+}
 
-        let events: Events = events.unwrap();
-        let events = events.events;
-        let seen_events = events.clone();
-        println!("All events:");
-        for x in seen_events.clone() {
-            println!("{}", x.print());
-        }
+pub async fn process_logs(folder: &SyncFolder) {
+    // Client events = local changes
+    // Client logs = logs of previous changes
+    // Runs with no new events too, for retries that are due
+    let client_events = get_events(&folder.changes_path);
+    check_client_logs(folder, client_events).await;
+}
 
-        let events_map: HashMap<(String, String), Event> = events
-            .into_iter()
-            .filter_map(|x| {
-                let mut copy = x.clone();
-
-                // TODO small optomization : since we only care about look ahead can split from current position and search to the end
-
-                // if remove is followed by modify, ignore the remove event
-                let is_remove = x.event_type == "Remove";
-
-                let find_modify = if is_remove {
-                    seen_events.iter().find(|y| {
-                        (y.event_type == "Modify" || y.event_type == "Rename") && y.path == x.path
-                    })
-                } else {
-                    None
-                };
-
-                let ignore_modify = find_modify.is_some() && find_modify.unwrap().time > x.time;
-
-                //if modify is followed by remove, ignore the modify event
-                let is_modify = x.event_type == "Modify" || x.event_type == "Rename";
-
-                let find_remove = if is_modify {
-                    seen_events.iter().find(|y| {
-                        y.event_type == "Remove"
-                            && (y.path == x.path || x.path.contains(&format!("{}/", &y.path)))
-                    })
-                } else {
-                    None
-                };
-
-                let ignore_remove = find_remove.is_some() && find_remove.unwrap().time > x.time;
-
-                // Rename 
-                if is_modify && x.event_type != "Rename" {
-                    let rename_event = seen_events.iter().find(|y| {
-                        let split: Vec<_> = y.path.split("$-$").collect();
-                        let old_path = split[0].to_string();
-                        let matches = y.event_type == "Rename"
-                            && !x.path.contains("$-$")
-                            && x.path.contains(&old_path);
-                        matches
-                    });
-
-                    if let Some(rename_event) = rename_event {
-                        let split: Vec<_> = rename_event.path.split("$-$").collect();
-                        let old_path = split[0].to_string();
-                        let new_path = split[1].to_string();
-                        copy.path =
-                            format!("{}$-${}", x.path, x.path.replace(&old_path, &new_path));
-                    }
-                }
+async fn check_client_logs(folder: &SyncFolder, client_events: Vec<Event>) {
+    // The rules may have changed since the events were queued
+    let client_events: Vec<Event> = client_events
+        .into_iter()
+        .filter(|c| !folder.is_ignored(c.path()))
+        .collect();
+
+    // Earlier failures go first, changes to the same paths have to follow them
+    let mut queued = queue::take_due(folder, &client_events);
+    queued.extend(client_events.into_iter().map(|event| QueuedRetry {
+        event,
+        attempts: 0,
+        next_attempt: 0,
+        held: false,
+    }));
+    if queued.is_empty() {
+        return;
+    }
 
-                if ignore_modify || ignore_remove {
-                    None
-                } else {
-                    Some((
-                        (copy.path.clone(), copy.event_type.clone()),
-                        Event {
-                            path: copy.path,
-                            event_type: copy.event_type,
-                            time: copy.time,
-                        },
-                    ))
-                }
-            })
-            .collect();
+    for c in queued.iter().map(|q| &q.event) {
+        if let EventKind::Create { path } | EventKind::Modify { path } = &c.kind
+            && let Ok(meta) = fs::metadata(folder.local_path(path))
+            && meta.is_file()
+            && !folder.is_ignored(path)
+        {
+            progress::enqueue(&folder.remote_path(path), Direction::Upload, meta.len());
+        }
+    }
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(path)
-            .unwrap();
+    let base = Mutex::new(Manifest::load(&folder.manifest_path));
+    let events: Vec<Event> = queued.iter().map(|q| q.event.clone()).collect();
+    let results = queue::send_events(folder, &events, &base).await;
+    base.into_inner().unwrap().save(&folder.manifest_path);
+
+    // Only what the server took goes in the change feed, the rest is retried later
+    let sent: Vec<Event> = events
+        .iter()
+        .zip(&results)
+        .filter(|(_, result)| **result == Sent::Done)
+        .map(|(event, _)| event.clone())
+        .collect();
+    queue::reschedule(folder, queued.into_iter().zip(results).collect());
+    publish(folder, &sent).await;
+}
 
-        let mut events: Vec<Event> = events_map.values().cloned().collect();
-        events.sort_by_key(|x| x.time);
-        for event in events {
-            let _ = file.write(event.print().as_bytes());
-        }
+/// Records changes the server took in the pair's log and sends them to the change feed, so other
+/// devices pick them up
+pub async fn publish(folder: &SyncFolder, events: &[Event]) {
+    if events.is_empty() {
+        return;
     }
 
-    if is_logs {
-        
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(&folder.log_path) {
+        for event in events {
+            let _ = file.write(to_line(event).as_bytes());
+        }
     }
+    send_logs(events.iter().map(|e| folder.to_remote(e)).collect()).await;
 }
 
-pub async fn process_logs() {
-    // Client events = local changes
-    // Client logs = logs of previous changes
+/// Position in the server's change feed the pair has applied up to
+fn load_cursor(folder: &SyncFolder) -> Option<u64> {
+    fs::read_to_string(folder.staging_path("cursor")).ok()?.trim().parse().ok()
+}
 
-    let client_events = get_events(&CONFIG.changes_path);
-    let client_logs = get_events(&CONFIG.log_path);
-    let server_logs = fetch_logs().await;
+fn save_cursor(folder: &SyncFolder, cursor: u64) {
+    let _ = fs::create_dir_all(folder.staging_path(""));
+    let _ = fs::write(folder.staging_path("cursor"), cursor.to_string());
+}
 
-    // TODO split server logs at position of last client log using a delimiter on the last line
+/// Applies what other devices changed since the stored cursor. Without a cursor, or when the server
+/// no longer has changes that old, the cursor is just caught up and a full reconcile does the
+/// syncing. Reconcile also runs once per start and whenever a change fails.
+pub async fn pull_changes(folder: &SyncFolder) {
+    let mut cursor = load_cursor(folder);
+    let mut needs_reconcile = cursor.is_none() || !folder.reconciled.load(Ordering::Relaxed);
+    let mut base = Manifest::load(&folder.manifest_path);
+
+    loop {
+        match fetch_changes(cursor.unwrap_or(0)).await {
+            ChangesResult::Feed(feed) => {
+                if cursor.is_some() {
+                    for change in &feed.changes {
+                        if change.origin.as_deref() == Some(DEVICE_ID.as_str()) {
+                            continue;
+                        }
+                        let Some(event) = folder.from_remote(&change.event) else {
+                            continue;
+                        };
+                        if !apply_change(folder, &event, &mut base).await {
+                            needs_reconcile = true;
+                        }
+                    }
+                }
 
-    if server_logs.is_some() && client_events.is_some() {
-        let t1 = tokio::spawn(check_client_logs(client_events.unwrap()));
-        let t2 = tokio::spawn(check_server_logs(client_logs, server_logs.unwrap()));
-        let _ = tokio::join!(t1, t2);
-        return;
+                cursor = Some(feed.cursor);
+                save_cursor(folder, feed.cursor);
+                if !feed.more {
+                    break;
+                }
+            }
+            ChangesResult::Gone(latest) => {
+                save_cursor(folder, latest);
+                needs_reconcile = true;
+                break;
+            }
+            ChangesResult::Failed => {
+                base.save(&folder.manifest_path);
+                return;
+            }
+        }
     }
+    base.save(&folder.manifest_path);
 
-    if server_logs.is_some() {
-        let t2 = tokio::spawn(check_server_logs(client_logs, server_logs.unwrap()));
-        let _ = t2.await;
-        return;
+    if needs_reconcile {
+        reconcile(folder).await;
+        folder.reconciled.store(true, Ordering::Relaxed);
     }
+}
 
-    if client_events.is_some() {
-        let t1 = tokio::spawn(check_client_logs(client_events.unwrap()));
-        let _ = t1.await;
+/// Whether the local copy of `path` is still the version last synced, so the server's can replace it
+fn unchanged_locally(folder: &SyncFolder, path: &str, base: &Manifest) -> bool {
+    let local = folder.local_path(path);
+    match (file_entry(&local), base.files.get(path)) {
+        (Some(local), Some(synced)) => local.hash == synced.hash,
+        (None, _) => true,
+        (Some(_), None) => false,
     }
 }
 
-async fn check_server_logs(client_logs: Option<Events>, server_logs: Events) {
-    if client_logs.is_some() {
-        let client_logs = client_logs.unwrap();
-        for s in server_logs.events {
-            // If the client side logs doesn't contain an entry from the server, update files locally
-            if !client_logs.events.contains(&s) {
-                edit_client_side(s.event_type, s.path);
+/// Repeats a change made on another device locally, returning false if it couldn't be
+async fn apply_change(folder: &SyncFolder, event: &Event, base: &mut Manifest) -> bool {
+    let root = &folder.local;
+    if folder.is_ignored(event.path()) {
+        return true;
+    }
+
+    match &event.kind {
+        EventKind::Create { path } | EventKind::Modify { path } => {
+            // Local edits win here, they are uploaded against the old base and conflict there
+            if root.join(path).is_dir() || !unchanged_locally(folder, path, base) {
+                return true;
+            }
+            if !get_file(folder, path.clone()).await {
+                return false;
+            }
+            if let Some(entry) = file_entry(&root.join(path)) {
+                base.files.insert(path.clone(), entry);
             }
+            true
         }
-        return;
-    }
+        EventKind::Delete { path } => {
+            if root.join(path).is_file() && !unchanged_locally(folder, path, base) {
+                return true;
+            }
+            if !delete_file(folder, path.clone(), base).await {
+                return false;
+            }
+            // Edits kept inside a deleted folder are then new files, reconcile sends them again
+            let inside = format!("{}/", path);
+            base.files.retain(|p, _| p != path && !p.starts_with(&inside));
+            true
+        }
+        EventKind::Rename { from, to } => {
+            let (source, target) = (root.join(from), root.join(to));
+            if !source.exists() || target.exists() {
+                return !source.exists() && target.exists();
+            }
+            if let Some(parent) = target.parent() {
+                echo::expect_folder(parent);
+                let _ = fs::create_dir_all(parent);
+            }
+            echo::expect_gone(&source);
+            if source.is_dir() {
+                echo::expect_folder(&target);
+            } else {
+                let hash = base.files.get(from).map(|e| e.hash.clone()).unwrap_or_default();
+                if let Some(entry) = stat_entry(&source, hash) {
+                    echo::expect_file(&target, entry);
+                }
+            }
+            if fs::rename(&source, &target).is_err() {
+                return false;
+            }
 
-    for s in server_logs.events {
-        edit_client_side(s.event_type, s.path);
+            base.rename(from, to);
+            true
+        }
+        EventKind::Mkdir { path } => {
+            echo::expect_folder(&root.join(path));
+            fs::create_dir_all(root.join(path)).is_ok()
+        }
     }
 }
 
-async fn check_client_logs(client_events: Events) {
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open(&CONFIG.log_path)
-        .unwrap();
+/// Brings local storage and the server in line by diffing both manifests against the one recorded
+/// after the last reconciliation, so changes the watcher missed or made while offline still converge.
+pub async fn reconcile(folder: &SyncFolder) {
+    let Some(mut remote) = fetch_manifest(folder).await else {
+        return;
+    };
+
+    let base = Manifest::load(&folder.manifest_path);
+    let cache = load_index(folder).unwrap_or_else(|| base.clone());
+    let rules = folder.ignore.read().unwrap().clone();
+    let root = folder.local.clone();
+    let local = tokio::task::spawn_blocking(move || build_manifest(&root, &cache, &rules)).await;
+    let Ok(local) = local else {
+        return;
+    };
+    save_index(folder, &local);
+
+    // Ignored files are neither downloaded nor deleted on the server, just left alone there
+    let rules = folder.ignore.read().unwrap().clone();
+    remote.files.retain(|path, _| !rules.is_ignored(path, false));
+
+    // Anything both sides already agree on is synced, anything gone from both is forgotten
+    let mut synced = base.clone();
+    synced
+        .files
+        .retain(|path, _| local.files.contains_key(path) || remote.files.contains_key(path));
+    for (path, entry) in &local.files {
+        if remote.files.get(path).is_some_and(|r| r.hash == entry.hash) {
+            synced.files.insert(path.clone(), entry.clone());
+        }
+    }
 
-    let client_events = client_events;
-    let events = client_events.events.clone();
+    let synced = Mutex::new(synced);
+    let actions = plan(&local, &remote, &base);
+    for action in &actions {
+        match action {
+            Action::Upload(path) => {
+                progress::enqueue(&folder.remote_path(path), Direction::Upload, local.files[path].size)
+            }
+            Action::Download(path) => progress::enqueue(
+                &folder.remote_path(path),
+                Direction::Download,
+                remote.files[path].size,
+            ),
+            Action::Conflict(_) | Action::DeleteRemote(_) | Action::DeleteLocal(_) => (),
+        }
+    }
 
-    for c in events {
-        edit_server_side(c.event_type.clone(), c.path.clone());
-        let _ = file.write(c.print().as_bytes());
+    // Each action is for a different path, so they can run side by side
+    let outcomes: Vec<(Action, bool)> = stream::iter(actions)
+        .map(|action| async {
+            let done = match &action {
+                Action::Upload(path) => {
+                    let base_hash = base.files.get(path).map(|e| e.hash.clone()).unwrap_or_default();
+                    match send_file(folder, path.clone(), Some(base_hash)).await {
+                        UploadResult::Done => {
+                            synced.lock().unwrap().files.insert(path.clone(), local.files[path].clone());
+                            true
+                        }
+                        UploadResult::Conflict => {
+                            let resolved = resolve_conflict(folder, path).await;
+                            synced.lock().unwrap().files.extend(resolved);
+                            true
+                        }
+                        UploadResult::Failed => false,
+                    }
+                }
+                Action::Conflict(path) => {
+                    let resolved = resolve_conflict(folder, path).await;
+                    synced.lock().unwrap().files.extend(resolved);
+                    true
+                }
+                Action::Download(path) => {
+                    let done = get_file(folder, path.clone()).await;
+                    if done {
+                        synced.lock().unwrap().files.insert(path.clone(), remote.files[path].clone());
+                    }
+                    done
+                }
+                Action::DeleteRemote(path) => {
+                    let done = send_delete_file(folder, path.clone()).await;
+                    if done {
+                        synced.lock().unwrap().files.remove(path);
+                    }
+                    done
+                }
+                Action::DeleteLocal(path) => {
+                    let done = delete_file(folder, path.clone(), &base).await;
+                    if done {
+                        synced.lock().unwrap().files.remove(path);
+                    }
+                    done
+                }
+            };
+            (action, done)
+        })
+        .buffer_unordered(CONFIG.transfer_concurrency.max(1))
+        .collect()
+        .await;
+
+    // Local changes that didn't make it are retried like those from the journal
+    let failed = outcomes
+        .into_iter()
+        .filter_map(|(action, done)| match action {
+            _ if done => None,
+            Action::Upload(path) => Some(EventKind::Modify { path }),
+            Action::DeleteRemote(path) => Some(EventKind::Delete { path }),
+            _ => None,
+        })
+        .map(|kind| {
+            let retry = QueuedRetry {
+                event: Event::new(kind),
+                attempts: 0,
+                next_attempt: 0,
+                held: false,
+            };
+            (retry, Sent::Failed)
+        })
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        queue::reschedule(folder, failed);
     }
 
-    send_logs(client_events).await;
+    let synced = synced.into_inner().unwrap();
+    synced.save(&folder.manifest_path);
 }
 
-pub fn get_events(path: &str) -> Option<Events> {
-    let events = toml::from_str(&fs::read_to_string(path).unwrap());
-    if events.is_ok() {
-        events.unwrap()
-    } else {
-        None
-    }
+pub fn get_events(path: &Path) -> Vec<Event> {
+    parse_lines(&fs::read_to_string(path).unwrap_or_default())
 }
 
 pub async fn log_err<T: std::fmt::Debug>(err: T) {
-    let _ = fs::write(&CONFIG.error_logs, format!("{:#?}", err));
+    activity::failed(&format!("{:?}", err), None, None);
 }
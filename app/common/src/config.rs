@@ -0,0 +1,93 @@
+//! Where the desktop client keeps its config, worked out the same way by the client and `dsync` so
+//! the CLI finds the `control.json` of the client it's meant to talk to.
+
+use std::path::{Path, PathBuf};
+
+/// Overrides the config file location, `--config <path>` on the command line wins over it
+pub const CONFIG_ENV: &str = "DRIVE_SYNC_CONFIG";
+
+/// Folder holding `config.toml`: `$XDG_CONFIG_HOME/drive_sync` on Linux, the roaming AppData
+/// folder on Windows
+pub fn config_dir() -> PathBuf {
+    let base = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
+    if cfg!(target_os = "linux") {
+        base.join("drive_sync")
+    } else {
+        base.join("Drive_Sync")
+    }
+}
+
+/// Takes `--config <path>` or `--config=<path>` out of `args`, the last one wins
+pub fn take_config_arg(args: &mut Vec<String>) -> Option<PathBuf> {
+    let mut path = None;
+    let mut i = 0;
+    while i < args.len() {
+        if let Some(value) = args[i].strip_prefix("--config=") {
+            path = Some(PathBuf::from(value));
+            args.remove(i);
+        } else if args[i] == "--config" && i + 1 < args.len() {
+            path = Some(PathBuf::from(args.remove(i + 1)));
+            args.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+    path
+}
+
+/// The config file in use, from the `--config` argument, then `DRIVE_SYNC_CONFIG`, then the
+/// config folder
+pub fn config_path(arg: Option<PathBuf>) -> PathBuf {
+    if let Some(path) = arg {
+        return path;
+    }
+
+    match std::env::var_os(CONFIG_ENV) {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => config_dir().join("config.toml"),
+    }
+}
+
+/// Written by the running client next to its config, holding the port and token of its control API
+pub fn control_file_path(config: &Path) -> PathBuf {
+    config.with_file_name("control.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn config_arg_is_taken_out_in_both_forms() {
+        let mut list = args(&["status", "--config", "/tmp/a.toml"]);
+        assert_eq!(take_config_arg(&mut list), Some(PathBuf::from("/tmp/a.toml")));
+        assert_eq!(list, args(&["status"]));
+
+        let mut list = args(&["--config=/tmp/b.toml", "sync"]);
+        assert_eq!(take_config_arg(&mut list), Some(PathBuf::from("/tmp/b.toml")));
+        assert_eq!(list, args(&["sync"]));
+    }
+
+    #[test]
+    fn last_config_arg_wins_and_a_dangling_flag_is_left() {
+        let mut list = args(&["--config", "/a", "--config=/b", "state", "--config"]);
+        assert_eq!(take_config_arg(&mut list), Some(PathBuf::from("/b")));
+        assert_eq!(list, args(&["state", "--config"]));
+
+        assert_eq!(take_config_arg(&mut args(&["queue"])), None);
+    }
+
+    #[test]
+    fn argument_wins_and_control_file_sits_next_to_config() {
+        let config = config_path(Some(PathBuf::from("/etc/sync/config.toml")));
+        assert_eq!(config, PathBuf::from("/etc/sync/config.toml"));
+        assert_eq!(
+            control_file_path(&config),
+            PathBuf::from("/etc/sync/control.json")
+        );
+    }
+}
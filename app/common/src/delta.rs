@@ -0,0 +1,388 @@
+//! The delta format both sides agree on. The server publishes a [`Signature`] of its copy of a
+//! file, the client answers with a delta against it: a little endian u32 block size followed by
+//! copy ops (`0`, u64 block index) and literal ops (`1`, u32 length, bytes).
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const COPY_OP: u8 = 0;
+pub const LITERAL_OP: u8 = 1;
+/// Largest block size a delta may use, the rebuild holds one block in memory
+pub const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+/// Literal runs are split so a single op never needs more than this in memory
+const LITERAL_MAX: usize = 1024 * 1024;
+const READ_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: String,
+}
+
+/// Per block checksums of the stored copy of a file, what a client needs to work out a delta.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Signature {
+    pub block_size: usize,
+    pub size: u64,
+    pub hash: String,
+    pub blocks: Vec<BlockSignature>,
+}
+
+/// The two halves of rsync's weak checksum, kept apart so the window can be rolled.
+fn checksum_parts(data: &[u8]) -> (u32, u32) {
+    let mut a: u32 = 0;
+    let mut b: u32 = 0;
+    for (i, byte) in data.iter().enumerate() {
+        a = a.wrapping_add(*byte as u32);
+        b = b.wrapping_add((data.len() - i) as u32 * *byte as u32);
+    }
+    (a & 0xffff, b & 0xffff)
+}
+
+/// rsync's weak checksum, cheap to roll forward one byte at a time on the client.
+pub fn weak_checksum(data: &[u8]) -> u32 {
+    let (a, b) = checksum_parts(data);
+    a | (b << 16)
+}
+
+/// Slides a `len` byte window one byte forward, dropping `out` and taking in `new`.
+fn roll(a: u32, b: u32, len: usize, out: u8, new: u8) -> (u32, u32) {
+    let a = a.wrapping_sub(out as u32).wrapping_add(new as u32) & 0xffff;
+    let b = b
+        .wrapping_sub((len as u32).wrapping_mul(out as u32))
+        .wrapping_add(a)
+        & 0xffff;
+    (a, b)
+}
+
+pub fn strong_checksum(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))[..16].to_string()
+}
+
+/// Roughly the square root of the file size, like rsync, so the signature stays small.
+fn block_size_for(size: u64) -> usize {
+    ((size as f64).sqrt() as usize)
+        .next_multiple_of(1024)
+        .clamp(2 * 1024, 64 * 1024)
+}
+
+pub fn build_signature(path: &Path) -> io::Result<Signature> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let block_size = block_size_for(size);
+
+    let mut hasher = Sha256::new();
+    let mut blocks = Vec::new();
+    let mut buffer = vec![0u8; block_size];
+
+    loop {
+        let mut filled = 0;
+        while filled < block_size {
+            let read = file.read(&mut buffer[filled..])?;
+            if read == 0 {
+                break;
+            }
+            filled += read;
+        }
+
+        if filled == 0 {
+            break;
+        }
+
+        let block = &buffer[..filled];
+        hasher.update(block);
+        blocks.push(BlockSignature {
+            weak: weak_checksum(block),
+            strong: strong_checksum(block),
+        });
+    }
+
+    Ok(Signature {
+        block_size,
+        size,
+        hash: format!("{:x}", hasher.finalize()),
+        blocks,
+    })
+}
+
+struct DeltaWriter<W: Write> {
+    out: W,
+    written: u64,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    fn copy(&mut self, index: usize) -> io::Result<()> {
+        self.out.write_all(&[COPY_OP])?;
+        self.out.write_all(&(index as u64).to_le_bytes())?;
+        self.written += 9;
+        Ok(())
+    }
+
+    fn literal(&mut self, data: &[u8]) -> io::Result<()> {
+        for part in data.chunks(LITERAL_MAX) {
+            self.out.write_all(&[LITERAL_OP])?;
+            self.out.write_all(&(part.len() as u32).to_le_bytes())?;
+            self.out.write_all(part)?;
+            self.written += 5 + part.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the ops turning the file behind `signature` into `local` to `out`, returning the delta
+/// size. Blocks are matched at any offset with a rolling weak checksum, confirmed by the strong one.
+pub fn compute_delta(local: &Path, signature: &Signature, out: &Path) -> io::Result<u64> {
+    let block_size = signature.block_size;
+    let last_len = match signature.size % block_size as u64 {
+        0 => block_size,
+        rem => rem as usize,
+    };
+
+    let mut table: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in signature.blocks.iter().enumerate() {
+        table.entry(block.weak).or_default().push(i);
+    }
+
+    let find = |window: &[u8], weak: u32| -> Option<usize> {
+        let candidates = table.get(&weak)?;
+        let strong = strong_checksum(window);
+        candidates.iter().copied().find(|&i| {
+            let len = if i + 1 == signature.blocks.len() {
+                last_len
+            } else {
+                block_size
+            };
+            len == window.len() && signature.blocks[i].strong == strong
+        })
+    };
+
+    let mut input = File::open(local)?;
+    let mut delta = DeltaWriter {
+        out: BufWriter::new(File::create(out)?),
+        written: 4,
+    };
+    delta.out.write_all(&(block_size as u32).to_le_bytes())?;
+
+    let mut buf: Vec<u8> = Vec::new();
+    let mut chunk = vec![0u8; READ_SIZE];
+    let mut pos = 0;
+    let mut literal_start = 0;
+    let mut eof = false;
+    let mut rolling: Option<(u32, u32)> = None;
+
+    loop {
+        if buf.len() < pos + block_size && !eof {
+            if pos - literal_start >= LITERAL_MAX {
+                delta.literal(&buf[literal_start..pos])?;
+                literal_start = pos;
+            }
+            buf.drain(..literal_start);
+            pos -= literal_start;
+            literal_start = 0;
+
+            let read = input.read(&mut chunk)?;
+            if read == 0 {
+                eof = true;
+            } else {
+                buf.extend_from_slice(&chunk[..read]);
+            }
+            continue;
+        }
+
+        if pos >= buf.len() {
+            break;
+        }
+
+        let end = buf.len().min(pos + block_size);
+        let window = &buf[pos..end];
+
+        // Only the final short block of the server copy can match a short tail, try it once
+        if window.len() < block_size {
+            let (a, b) = checksum_parts(window);
+            if let Some(i) = find(window, a | (b << 16)) {
+                delta.literal(&buf[literal_start..pos])?;
+                delta.copy(i)?;
+                literal_start = end;
+            }
+            break;
+        }
+
+        let (a, b) = rolling.unwrap_or_else(|| checksum_parts(window));
+        if let Some(i) = find(window, a | (b << 16)) {
+            delta.literal(&buf[literal_start..pos])?;
+            delta.copy(i)?;
+            pos = end;
+            literal_start = pos;
+            rolling = None;
+            continue;
+        }
+
+        rolling = buf
+            .get(end)
+            .map(|&new| roll(a, b, block_size, buf[pos], new));
+        pos += 1;
+    }
+
+    delta.literal(&buf[literal_start..])?;
+    delta.out.flush()?;
+    Ok(delta.written)
+}
+
+/// Rebuilds a file from `basis` and a delta made against its signature, writing it to `out`.
+/// A delta that doesn't fit the basis fails with [`io::ErrorKind::InvalidData`].
+pub fn apply_delta(basis: &Path, delta: &[u8], out: &Path) -> io::Result<()> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed delta");
+    let take = |pos: &mut usize, n: usize| -> io::Result<&[u8]> {
+        let slice = delta.get(*pos..*pos + n).ok_or_else(invalid)?;
+        *pos += n;
+        Ok(slice)
+    };
+
+    let mut basis = File::open(basis)?;
+    let basis_len = basis.metadata()?.len();
+    let mut output = File::create(out)?;
+
+    let mut pos = 0;
+    let block_size = u32::from_le_bytes(take(&mut pos, 4)?.try_into().unwrap()) as u64;
+    if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+        return Err(invalid());
+    }
+    let mut buffer = vec![0u8; block_size as usize];
+
+    while pos < delta.len() {
+        match take(&mut pos, 1)?[0] {
+            COPY_OP => {
+                let index = u64::from_le_bytes(take(&mut pos, 8)?.try_into().unwrap());
+                let start = index.checked_mul(block_size).ok_or_else(invalid)?;
+                if start >= basis_len {
+                    return Err(invalid());
+                }
+
+                let len = block_size.min(basis_len - start) as usize;
+                basis.seek(SeekFrom::Start(start))?;
+                basis.read_exact(&mut buffer[..len])?;
+                output.write_all(&buffer[..len])?;
+            }
+            LITERAL_OP => {
+                let len = u32::from_le_bytes(take(&mut pos, 4)?.try_into().unwrap()) as usize;
+                output.write_all(take(&mut pos, len)?)?;
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    output.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// Bytes that don't repeat within a block, so every block of the basis is told apart
+    fn pattern(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(2654435761).wrapping_add(1);
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// Sends `target` as a delta against `basis` and rebuilds it, returning the rebuilt file and the
+    /// delta size
+    fn round_trip(basis: &[u8], target: &[u8]) -> (Vec<u8>, u64) {
+        let dir = TempDir::new("delta");
+        let basis = dir.write("basis", basis);
+        let target = dir.write("target", target);
+        let delta = dir.0.join("delta");
+        let rebuilt = dir.0.join("rebuilt");
+
+        let signature = build_signature(&basis).unwrap();
+        let size = compute_delta(&target, &signature, &delta).unwrap();
+        let delta = std::fs::read(&delta).unwrap();
+        assert_eq!(size, delta.len() as u64);
+
+        apply_delta(&basis, &delta, &rebuilt).unwrap();
+        (std::fs::read(&rebuilt).unwrap(), size)
+    }
+
+    #[test]
+    fn unchanged_file_is_all_copies() {
+        let data = pattern(10 * 2048, 1);
+        let (rebuilt, size) = round_trip(&data, &data);
+        assert_eq!(rebuilt, data);
+        assert_eq!(size, 4 + 10 * 9);
+    }
+
+    #[test]
+    fn edit_in_the_middle_only_sends_the_edit() {
+        let basis = pattern(50 * 2048, 2);
+        let mut target = basis.clone();
+        target.splice(30_000..30_010, *b"inserted!!!!!!!!");
+
+        let (rebuilt, size) = round_trip(&basis, &target);
+        assert_eq!(rebuilt, target);
+        assert!(size < 3 * 2048, "delta of {} bytes", size);
+    }
+
+    #[test]
+    fn short_last_block_is_copied() {
+        let basis = pattern(5 * 2048 + 700, 3);
+        let mut target = basis.clone();
+        target[100] ^= 0xff;
+
+        let (rebuilt, size) = round_trip(&basis, &target);
+        assert_eq!(rebuilt, target);
+        assert!(size < 2 * 2048, "delta of {} bytes", size);
+    }
+
+    #[test]
+    fn empty_files() {
+        assert_eq!(round_trip(b"", b"").0, b"");
+        assert_eq!(round_trip(b"", b"new content").0, b"new content");
+        assert_eq!(round_trip(&pattern(4096, 4), b"").0, b"");
+    }
+
+    #[test]
+    fn fully_changed_file_is_all_literals() {
+        let basis = pattern(8 * 2048, 5);
+        let target = pattern(8 * 2048 + 123, 6);
+
+        let (rebuilt, size) = round_trip(&basis, &target);
+        assert_eq!(rebuilt, target);
+        assert!(size >= target.len() as u64);
+    }
+
+    #[test]
+    fn oversized_block_size_is_refused() {
+        let dir = TempDir::new("delta");
+        let basis = dir.write("basis", b"basis");
+        let delta = (MAX_BLOCK_SIZE as u32 + 1).to_le_bytes();
+
+        let err = apply_delta(&basis, &delta, &dir.0.join("out")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn copy_past_the_basis_is_refused() {
+        let dir = TempDir::new("delta");
+        let basis = dir.write("basis", pattern(2048, 7));
+        let mut delta = 2048u32.to_le_bytes().to_vec();
+        delta.push(COPY_OP);
+        delta.extend_from_slice(&5u64.to_le_bytes());
+
+        let err = apply_delta(&basis, &delta, &dir.0.join("out")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
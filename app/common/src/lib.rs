@@ -0,0 +1,513 @@
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+pub mod config;
+pub mod delta;
+pub mod manifest;
+#[cfg(test)]
+mod testing;
+
+/// Bumped whenever the wire format changes in a way older peers can't read
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What happened to a path. Paths are relative to the storage root and `/` separated.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EventKind {
+    Create { path: String },
+    Modify { path: String },
+    Delete { path: String },
+    Rename { from: String, to: String },
+    Mkdir { path: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    #[serde(flatten)]
+    pub kind: EventKind,
+    /// Milliseconds since the epoch on the machine that saw the change
+    pub time: i64,
+}
+
+impl Event {
+    /// An event that happened just now
+    pub fn new(kind: EventKind) -> Event {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0);
+
+        Event { kind, time }
+    }
+
+    /// The path the change leaves behind, the destination for a rename
+    pub fn path(&self) -> &str {
+        match &self.kind {
+            EventKind::Create { path }
+            | EventKind::Modify { path }
+            | EventKind::Delete { path }
+            | EventKind::Mkdir { path } => path,
+            EventKind::Rename { to, .. } => to,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            EventKind::Create { path } => write!(f, "create {:?}", path),
+            EventKind::Modify { path } => write!(f, "modify {:?}", path),
+            EventKind::Delete { path } => write!(f, "delete {:?}", path),
+            EventKind::Rename { from, to } => write!(f, "rename {:?} -> {:?}", from, to),
+            EventKind::Mkdir { path } => write!(f, "mkdir {:?}", path),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    Malformed(String),
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Malformed(e) => write!(f, "malformed events: {}", e),
+            ProtocolError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported protocol version {} (expected {})",
+                v, PROTOCOL_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// A batch of events as sent between client and server
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u32,
+    pub events: Vec<Event>,
+}
+
+impl Envelope {
+    pub fn new(events: Vec<Event>) -> Envelope {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            events,
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.version == PROTOCOL_VERSION
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("events always serialize")
+    }
+
+    pub fn decode(content: &str) -> Result<Envelope, ProtocolError> {
+        // Read the version on its own first so a newer format is reported as such, not as garbage
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+
+        let version: Version = serde_json::from_str(content)
+            .map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+        if version.version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version.version));
+        }
+
+        serde_json::from_str(content).map_err(|e| ProtocolError::Malformed(e.to_string()))
+    }
+}
+
+/// An event as recorded by the server, numbered in the order it was accepted
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    pub seq: u64,
+    /// Device that made the change, so it can skip its own changes in the feed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Reply to `GET /changes?since=<cursor>`: changes after the cursor, oldest first
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChangeFeed {
+    pub version: u32,
+    pub changes: Vec<Change>,
+    /// Where to continue from next time
+    pub cursor: u64,
+    /// More changes are waiting past `cursor`
+    pub more: bool,
+}
+
+/// Pushed over the websocket as soon as changes are accepted, so devices pull them right away
+/// instead of waiting for their next poll
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    pub version: u32,
+    pub changes: Vec<Change>,
+}
+
+impl Notification {
+    pub fn new(changes: Vec<Change>) -> Self {
+        Notification {
+            version: PROTOCOL_VERSION,
+            changes,
+        }
+    }
+}
+
+/// One event per line, how event logs are kept on disk
+pub fn to_line(event: &Event) -> String {
+    format!(
+        "{}\n",
+        serde_json::to_string(event).expect("events always serialize")
+    )
+}
+
+/// Reads an event log written with `to_line`, skipping lines that don't parse, such as a line
+/// cut short by a crash.
+pub fn parse_lines(content: &str) -> Vec<Event> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+fn is_under(path: &str, dir: &str) -> bool {
+    path == dir
+        || path
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// True when both events describe the same pending change, so only the later one is needed
+fn same_change(a: &EventKind, b: &EventKind) -> bool {
+    use EventKind::*;
+
+    match (a, b) {
+        (Create { path: a } | Modify { path: a }, Create { path: b } | Modify { path: b }) => a == b,
+        (Delete { path: a }, Delete { path: b }) | (Mkdir { path: a }, Mkdir { path: b }) => a == b,
+        (Rename { from: fa, to: ta }, Rename { from: fb, to: tb }) => fa == fb && ta == tb,
+        _ => false,
+    }
+}
+
+/// Reduces a log to the changes that still need applying, in order:
+/// - edits to a path that is deleted later are dropped, as is a delete undone by a later write
+/// - edits to a path that is renamed later are moved after the rename, at its new location
+/// - repeats of the same change are merged into the latest one
+pub fn compact(mut events: Vec<Event>) -> Vec<Event> {
+    use EventKind::*;
+
+    events.sort_by_key(|e| e.time);
+    let mut kept: Vec<Event> = Vec::new();
+    let mut deferred: Vec<(usize, Event)> = Vec::new();
+
+    let keep = |kept: &mut Vec<Event>, mut event: Event| {
+        if let Some(i) = kept.iter().position(|k| same_change(&k.kind, &event.kind)) {
+            let earlier = kept.remove(i);
+            if let (Create { .. }, Modify { path }) = (&earlier.kind, &event.kind) {
+                event.kind = Create { path: path.clone() };
+            }
+        }
+        kept.push(event);
+    };
+
+    for (i, event) in events.iter().enumerate() {
+        let later = &events[i + 1..];
+        let mut event = event.clone();
+
+        match &mut event.kind {
+            Create { path } | Modify { path } | Mkdir { path } => {
+                let deleted = later
+                    .iter()
+                    .any(|e| matches!(&e.kind, Delete { path: d } if is_under(path, d)));
+                if deleted {
+                    continue;
+                }
+
+                let mut moved = None;
+                for (j, e) in later.iter().enumerate() {
+                    if let Rename { from, to } = &e.kind
+                        && is_under(path, from)
+                    {
+                        *path = format!("{}{}", to, &path[from.len()..]);
+                        moved = Some((i + 1 + j, e.time));
+                    }
+                }
+
+                if let Some((j, time)) = moved {
+                    event.time = time;
+                    deferred.push((j, event));
+                    continue;
+                }
+            }
+            Delete { path } => {
+                let rewritten = later.iter().any(|e| match &e.kind {
+                    Create { path: p } | Modify { path: p } => p == path,
+                    Rename { to, .. } => to == path,
+                    _ => false,
+                });
+                if rewritten {
+                    continue;
+                }
+            }
+            Rename { .. } => {}
+        }
+
+        keep(&mut kept, event);
+
+        let (ready, waiting): (Vec<_>, Vec<_>) = deferred.drain(..).partition(|(j, _)| *j == i);
+        deferred = waiting;
+        for (_, event) in ready {
+            keep(&mut kept, event);
+        }
+    }
+
+    kept
+}
+
+/// Marks the events a later one makes redundant. Replaying the rest from any starting point ends
+/// in the same state, and unlike `compact` nothing is merged or reordered, so events numbered in a
+/// journal can be dropped without renumbering the others.
+pub fn superseded(events: &[Event]) -> Vec<bool> {
+    use EventKind::*;
+
+    let mut dropped = vec![false; events.len()];
+    let mut written: Vec<&str> = Vec::new();
+    let mut deleted: Vec<&str> = Vec::new();
+
+    for (i, event) in events.iter().enumerate().rev() {
+        match &event.kind {
+            Create { path } | Modify { path } => {
+                dropped[i] = written.contains(&path.as_str())
+                    || deleted.iter().any(|d| is_under(path, d));
+                written.push(path);
+            }
+            Mkdir { path } | Delete { path } => {
+                dropped[i] = deleted.iter().any(|d| is_under(path, d));
+                if matches!(event.kind, Delete { .. }) {
+                    deleted.push(path);
+                }
+            }
+            // Before the rename these paths held other content, so later changes to them don't count
+            Rename { from, to } => {
+                let overlaps = |p: &&str| {
+                    [from, to]
+                        .iter()
+                        .any(|r| is_under(p, r) || is_under(r, p))
+                };
+                written.retain(|p| !overlaps(p));
+                deleted.retain(|p| !overlaps(p));
+            }
+        }
+    }
+
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: EventKind, time: i64) -> Event {
+        Event { kind, time }
+    }
+
+    fn all_kinds(path: &str) -> Vec<Event> {
+        vec![
+            event(EventKind::Create { path: path.to_string() }, 1),
+            event(EventKind::Modify { path: path.to_string() }, 2),
+            event(EventKind::Delete { path: path.to_string() }, 3),
+            event(
+                EventKind::Rename {
+                    from: path.to_string(),
+                    to: format!("{}.moved", path),
+                },
+                4,
+            ),
+            event(EventKind::Mkdir { path: path.to_string() }, 5),
+        ]
+    }
+
+    #[test]
+    fn envelope_round_trips_every_kind() {
+        let envelope = Envelope::new(all_kinds("docs/report.txt"));
+        assert_eq!(Envelope::decode(&envelope.encode()), Ok(envelope));
+    }
+
+    #[test]
+    fn awkward_paths_round_trip() {
+        let paths = [
+            r#"quotes "inside" name.txt"#,
+            r"back\slash\dir\file",
+            "old$-$new.txt",
+            "line\nbreak\ttab",
+            "ünïcödé/ファイル.txt",
+            "[[events]]\nevent_type = \"Remove\"",
+        ];
+
+        for path in paths {
+            let envelope = Envelope::new(all_kinds(path));
+            assert_eq!(Envelope::decode(&envelope.encode()), Ok(envelope));
+
+            let log: String = all_kinds(path).iter().map(to_line).collect();
+            assert_eq!(parse_lines(&log), all_kinds(path));
+        }
+    }
+
+    #[test]
+    fn wire_format_is_tagged_json() {
+        let encoded = Envelope::new(vec![event(
+            EventKind::Rename {
+                from: "a".to_string(),
+                to: "b".to_string(),
+            },
+            7,
+        )])
+        .encode();
+
+        assert_eq!(
+            encoded,
+            r#"{"version":1,"events":[{"kind":"rename","from":"a","to":"b","time":7}]}"#
+        );
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut envelope = Envelope::new(all_kinds("a"));
+        envelope.version = PROTOCOL_VERSION + 1;
+
+        assert_eq!(
+            Envelope::decode(&envelope.encode()),
+            Err(ProtocolError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+        assert!(matches!(
+            Envelope::decode("[[events]]"),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn changes_round_trip() {
+        let change = Change {
+            seq: 42,
+            origin: Some("laptop".to_string()),
+            event: event(
+                EventKind::Rename {
+                    from: "a\"b".to_string(),
+                    to: "c$-$d".to_string(),
+                },
+                9,
+            ),
+        };
+        let feed = ChangeFeed {
+            version: PROTOCOL_VERSION,
+            changes: vec![change.clone()],
+            cursor: 42,
+            more: false,
+        };
+
+        let encoded = serde_json::to_string(&feed).unwrap();
+        assert_eq!(serde_json::from_str::<ChangeFeed>(&encoded).unwrap(), feed);
+
+        // Events logged before changes were numbered still read, without an origin
+        let line = r#"{"seq":1,"kind":"mkdir","path":"x","time":3}"#;
+        let change: Change = serde_json::from_str(line).unwrap();
+        assert_eq!(change.origin, None);
+        assert_eq!(change.event, event(EventKind::Mkdir { path: "x".to_string() }, 3));
+    }
+
+    #[test]
+    fn torn_lines_are_skipped() {
+        let mut log: String = all_kinds("a").iter().map(to_line).collect();
+        log.push_str(r#"{"kind":"modify","pa"#);
+
+        assert_eq!(parse_lines(&log), all_kinds("a"));
+    }
+
+    #[test]
+    fn compact_drops_edits_to_deleted_paths() {
+        let events = vec![
+            event(EventKind::Modify { path: "dir/a".to_string() }, 1),
+            event(EventKind::Modify { path: "b".to_string() }, 2),
+            event(EventKind::Delete { path: "dir".to_string() }, 3),
+            event(EventKind::Modify { path: "b".to_string() }, 4),
+        ];
+
+        assert_eq!(
+            compact(events),
+            vec![
+                event(EventKind::Delete { path: "dir".to_string() }, 3),
+                event(EventKind::Modify { path: "b".to_string() }, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn compact_moves_edits_after_renames() {
+        let events = vec![
+            event(EventKind::Create { path: "old/a".to_string() }, 1),
+            event(
+                EventKind::Rename {
+                    from: "old".to_string(),
+                    to: "new".to_string(),
+                },
+                2,
+            ),
+            event(EventKind::Delete { path: "c".to_string() }, 3),
+            event(EventKind::Modify { path: "c".to_string() }, 4),
+        ];
+
+        assert_eq!(
+            compact(events),
+            vec![
+                event(
+                    EventKind::Rename {
+                        from: "old".to_string(),
+                        to: "new".to_string(),
+                    },
+                    2,
+                ),
+                event(EventKind::Create { path: "new/a".to_string() }, 2),
+                event(EventKind::Modify { path: "c".to_string() }, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn superseded_keeps_order_and_renames() {
+        let kinds = vec![
+            EventKind::Create { path: "a".to_string() },
+            EventKind::Modify { path: "a".to_string() },
+            EventKind::Mkdir { path: "d".to_string() },
+            EventKind::Create { path: "d/x".to_string() },
+            EventKind::Modify { path: "b".to_string() },
+            EventKind::Rename {
+                from: "b".to_string(),
+                to: "c".to_string(),
+            },
+            EventKind::Delete { path: "d".to_string() },
+            EventKind::Delete { path: "c".to_string() },
+        ];
+        let events: Vec<Event> = kinds.into_iter().map(|k| event(k, 0)).collect();
+
+        assert_eq!(
+            superseded(&events),
+            vec![true, false, true, true, false, false, false, false]
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Snapshots of a storage root, built the same way on the server and on every device so their
+//! hashes can be compared directly.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, Metadata},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub size: u64,
+    pub mtime: i64,
+    pub hash: String,
+    /// Identifies the file across renames in the local index, 0 where unknown like in the
+    /// server's manifest
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub inode: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// Snapshot of every file under a storage root, keyed by its `/` separated relative path.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: BTreeMap<String, FileEntry>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Manifest {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) {
+        if let Ok(content) = serde_json::to_string(self) {
+            let _ = fs::write(path, content);
+        }
+    }
+
+    /// Moves the entries for `from`, or everything under it if it's a folder, to `to`
+    pub fn rename(&mut self, from: &str, to: &str) {
+        let prefix = format!("{}/", from);
+        let moved: Vec<String> = self
+            .files
+            .keys()
+            .filter(|p| *p == from || p.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for old in moved {
+            if let Some(entry) = self.files.remove(&old) {
+                self.files.insert(format!("{}{}", to, &old[from.len()..]), entry);
+            }
+        }
+    }
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn mtime(meta: &Metadata) -> i64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(meta)
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &Metadata) -> u64 {
+    0
+}
+
+/// Stats and hashes a single file.
+pub fn file_entry(path: &Path) -> Option<FileEntry> {
+    let meta = fs::metadata(path).ok()?;
+
+    Some(FileEntry {
+        size: meta.len(),
+        mtime: mtime(&meta),
+        hash: hash_file(path).ok()?,
+        inode: inode(&meta),
+    })
+}
+
+/// Stats a file whose hash is already known.
+pub fn stat_entry(path: &Path, hash: String) -> Option<FileEntry> {
+    let meta = fs::metadata(path).ok()?;
+
+    Some(FileEntry {
+        size: meta.len(),
+        mtime: mtime(&meta),
+        hash,
+        inode: inode(&meta),
+    })
+}
+
+/// Files under `dir` that `skip` lets through, as relative path, full path and metadata.
+/// Subfolders are walked in parallel.
+fn walk<F>(root: &Path, dir: &Path, skip: &F) -> Vec<(String, PathBuf, Metadata)>
+where
+    F: Fn(&str, bool) -> bool + Sync,
+{
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+
+        let Ok(relative) = path.strip_prefix(root) else {
+            continue;
+        };
+        let relative = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        if skip(&relative, meta.is_dir()) {
+            continue;
+        }
+
+        if meta.is_dir() {
+            dirs.push(path);
+            continue;
+        }
+
+        files.push((relative, path, meta));
+    }
+
+    let nested: Vec<_> = dirs
+        .par_iter()
+        .flat_map_iter(|dir| walk(root, dir, skip))
+        .collect();
+    files.extend(nested);
+    files
+}
+
+/// Walks `root` and builds a manifest, reusing hashes from `cache` when size, mtime and inode are
+/// unchanged. `skip` gets each relative path and whether it's a folder, skipped folders aren't
+/// walked at all. Both the walk and the hashing run on all cores, for folders with a lot of files.
+pub fn build_manifest<F>(root: &Path, cache: &Manifest, skip: F) -> Manifest
+where
+    F: Fn(&str, bool) -> bool + Sync,
+{
+    let files = walk(root, root, &skip)
+        .into_par_iter()
+        .filter_map(|(relative, path, meta)| {
+            let size = meta.len();
+            let mtime = mtime(&meta);
+            let inode = inode(&meta);
+
+            let hash = match cache.files.get(&relative) {
+                Some(cached)
+                    if cached.size == size
+                        && cached.mtime == mtime
+                        && (cached.inode == 0 || cached.inode == inode) =>
+                {
+                    cached.hash.clone()
+                }
+                _ => hash_file(&path).ok()?,
+            };
+
+            Some((relative, FileEntry { size, mtime, hash, inode }))
+        })
+        .collect();
+
+    Manifest { files }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn nested_files_get_slash_separated_paths() {
+        let dir = TempDir::new("manifest");
+        dir.write("a.txt", "a");
+        dir.write("docs/deep/b.txt", "b");
+
+        let manifest = build_manifest(&dir.0, &Manifest::default(), |_, _| false);
+        let paths: Vec<&str> = manifest.files.keys().map(String::as_str).collect();
+        assert_eq!(paths, ["a.txt", "docs/deep/b.txt"]);
+        assert_eq!(
+            manifest.files["a.txt"].hash,
+            hash_file(&dir.0.join("a.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn skipped_folders_are_not_walked() {
+        let dir = TempDir::new("manifest");
+        dir.write("keep.txt", "k");
+        dir.write("build/out.o", "o");
+        dir.write("notes/build", "a file, not a folder");
+
+        let manifest = build_manifest(&dir.0, &Manifest::default(), |relative, is_dir| {
+            is_dir && relative.ends_with("build")
+        });
+        assert!(manifest.files.contains_key("keep.txt"));
+        assert!(manifest.files.contains_key("notes/build"));
+        assert!(!manifest.files.contains_key("build/out.o"));
+    }
+
+    #[test]
+    fn unchanged_files_keep_their_cached_hash() {
+        let dir = TempDir::new("manifest");
+        dir.write("a.txt", "a");
+
+        let mut cache = build_manifest(&dir.0, &Manifest::default(), |_, _| false);
+        cache.files.get_mut("a.txt").unwrap().hash = "cached".to_string();
+        let manifest = build_manifest(&dir.0, &cache, |_, _| false);
+        assert_eq!(manifest.files["a.txt"].hash, "cached");
+
+        cache.files.get_mut("a.txt").unwrap().size += 1;
+        let manifest = build_manifest(&dir.0, &cache, |_, _| false);
+        assert_ne!(manifest.files["a.txt"].hash, "cached");
+    }
+
+    #[test]
+    fn rename_moves_folder_contents_only() {
+        let entry = FileEntry {
+            size: 1,
+            mtime: 0,
+            hash: String::new(),
+            inode: 0,
+        };
+        let mut manifest = Manifest::default();
+        for path in ["docs/a.txt", "docs/sub/b.txt", "docs2/c.txt", "docs"] {
+            manifest.files.insert(path.to_string(), entry.clone());
+        }
+
+        manifest.rename("docs", "papers");
+        let paths: Vec<&str> = manifest.files.keys().map(String::as_str).collect();
+        assert_eq!(paths, ["docs2/c.txt", "papers", "papers/a.txt", "papers/sub/b.txt"]);
+    }
+}
@@ -0,0 +1,16 @@
+-- Every change accepted from a user's devices or the website, numbered in the order it arrived
+CREATE TABLE IF NOT EXISTS changes (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id TEXT NOT NULL,
+    origin TEXT,
+    event TEXT NOT NULL,
+    accepted_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+CREATE INDEX IF NOT EXISTS changes_by_user ON changes (user_id, seq);
+
+-- Highest change dropped by retention for each user, cursors older than it can't be served
+CREATE TABLE IF NOT EXISTS journal_floor (
+    user_id TEXT PRIMARY KEY,
+    seq INTEGER NOT NULL
+);
@@ -0,0 +1,109 @@
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+/// Shared with the website backend, which issues the tokens
+pub static JWT_SECRET: Lazy<String> = Lazy::new(|| {
+    dotenv::dotenv().ok();
+    std::env::var("JWT_SECRET").expect("JWT_SECRET not set")
+});
+
+/// Claims of a token issued by `website/backend/src/routes/auth.rs::generate_jwt`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Data {
+    pub user: String,
+    pub admin: bool,
+    pub exp: usize,
+}
+
+#[derive(Clone)]
+pub struct AuthUser(pub Data);
+
+fn verify_token(token: &str, secret: &str) -> Result<AuthUser, (StatusCode, &'static str)> {
+    let data = decode::<Data>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::new(Algorithm::HS256),
+    )
+    .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid or expired token"))?;
+
+    // The user id names the folders the user is scoped to
+    let user = &data.claims.user;
+    if user.is_empty() || user == "." || user == ".." || user.contains(['/', '\\']) {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid user"));
+    }
+
+    Ok(AuthUser(data.claims))
+}
+
+pub fn get_user_from_request(req: &Request<Body>) -> Result<AuthUser, (StatusCode, &'static str)> {
+    let auth_header = req
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .ok_or((StatusCode::UNAUTHORIZED, "Missing Authorization header"))?;
+
+    let token = auth_header
+        .strip_prefix("Bearer ")
+        .ok_or((StatusCode::UNAUTHORIZED, "Invalid Bearer format"))?;
+
+    verify_token(token, &JWT_SECRET)
+}
+
+pub async fn auth_middleware(
+    mut req: Request<Body>,
+    next: Next,
+) -> Result<Response, (StatusCode, String)> {
+    let user = match get_user_from_request(&req) {
+        Ok(user) => user,
+        Err((status, message)) => return Err((status, message.to_string())),
+    };
+
+    req.extensions_mut().insert(user);
+
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{EncodingKey, Header, encode};
+
+    use super::*;
+
+    const SECRET: &str = "test secret";
+
+    fn token(user: &str, secret: &str) -> String {
+        let claims = Data {
+            user: user.to_string(),
+            admin: false,
+            exp: usize::MAX / 2,
+        };
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        encode(&Header::default(), &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn plain_user_ids_are_accepted() {
+        let AuthUser(data) = verify_token(&token("alice", SECRET), SECRET).unwrap();
+        assert_eq!(data.user, "alice");
+    }
+
+    #[test]
+    fn user_ids_that_are_not_one_folder_are_rejected() {
+        for user in ["", ".", "..", "alice/bob", "../bob", "alice\\bob"] {
+            let result = verify_token(&token(user, SECRET), SECRET);
+            assert!(result.is_err(), "{:?}", user);
+        }
+    }
+
+    #[test]
+    fn tokens_signed_with_another_secret_are_rejected() {
+        assert!(verify_token(&token("alice", "other secret"), SECRET).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use std::io;
+
+use axum::{
+    Extension, Json,
+    body::Bytes,
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use common::{
+    delta::{apply_delta, build_signature},
+    manifest::hash_file,
+};
+
+use crate::{auth::AuthUser, route_handlers::user_path, uploads::{move_into_place, uploads_dir}};
+
+pub const DELTA_BODY_LIMIT: usize = 50 * 1024 * 1024;
+
+pub async fn get_signature(
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    Path(file_path): Path<String>,
+) -> Response {
+    let Some(file_path) = user_path(&user.user, &file_path) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    if !file_path.is_file() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    match tokio::task::spawn_blocking(move || build_signature(&file_path)).await {
+        Ok(Ok(signature)) => (StatusCode::OK, Json(signature)).into_response(),
+        _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Applies a delta made against the signature whose hash is in `X-Delta-Basis`. The file is
+/// rebuilt next to the uploads and renamed into place so readers never see it half written.
+pub async fn post_delta(
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    Path(file_path): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    let Some(basis_hash) = header("X-Delta-Basis") else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let base_hash = header("X-Base-Hash");
+    let content_hash = header("X-Content-Hash");
+
+    let Some(target) = user_path(&user.user, &file_path) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let uploads = uploads_dir(&user.user);
+    let temp = uploads.join(format!("{:016x}.delta", rand::random::<u64>()));
+
+    let result = tokio::task::spawn_blocking(move || {
+        let current = hash_file(&target).map_err(|_| StatusCode::NOT_FOUND)?;
+        if base_hash.is_some_and(|base| base != current) {
+            return Err(StatusCode::CONFLICT);
+        }
+        if basis_hash != current {
+            return Err(StatusCode::PRECONDITION_FAILED);
+        }
+
+        std::fs::create_dir_all(&uploads).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let rebuilt = apply_delta(&target, &body, &temp).map_err(|e| {
+            if e.kind() == io::ErrorKind::InvalidData {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        });
+
+        let verified = rebuilt.and_then(|_| match content_hash {
+            Some(expected) if hash_file(&temp).ok().as_ref() != Some(&expected) => {
+                Err(StatusCode::UNPROCESSABLE_ENTITY)
+            }
+            _ => Ok(()),
+        });
+
+        match verified.and_then(|_| {
+            move_into_place(&temp, &target).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+        }) {
+            Ok(()) => Ok(()),
+            Err(status) => {
+                let _ = std::fs::remove_file(&temp);
+                Err(status)
+            }
+        }
+    })
+    .await;
+
+    match result {
+        Ok(Ok(())) => StatusCode::OK.into_response(),
+        Ok(Err(status)) => status.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
@@ -0,0 +1,314 @@
+use std::{str::FromStr, time::Duration};
+
+use common::{Change, Event, superseded};
+use sqlx::{
+    Row, SqlitePool,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteRow},
+};
+
+/// Changes older than this are dropped and devices that far behind do a full reconcile instead
+const RETENTION_SECS: i64 = 30 * 24 * 60 * 60;
+const COMPACT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Opens the journal database, creating it if needed. WAL lets the change feed be read while
+/// other requests append.
+pub async fn open(url: &str) -> Result<SqlitePool, sqlx::Error> {
+    let options = SqliteConnectOptions::from_str(url)?
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal);
+
+    let db = SqlitePool::connect_with(options).await?;
+    sqlx::migrate!().run(&db).await?;
+    Ok(db)
+}
+
+fn to_change(row: SqliteRow) -> Option<Change> {
+    let seq: i64 = row.get("seq");
+    let event: String = row.get("event");
+
+    Some(Change {
+        seq: seq as u64,
+        origin: row.get("origin"),
+        event: serde_json::from_str(&event).ok()?,
+    })
+}
+
+/// Every change `user` has made that the journal still holds, oldest first
+pub async fn all_changes(db: &SqlitePool, user: &str) -> Result<Vec<Change>, sqlx::Error> {
+    let rows = sqlx::query("SELECT seq, origin, event FROM changes WHERE user_id = ? ORDER BY seq")
+        .bind(user)
+        .fetch_all(db)
+        .await?;
+
+    Ok(rows.into_iter().filter_map(to_change).collect())
+}
+
+/// Numbers `events` and appends them to the user's journal in one transaction, so either all of
+/// them are recorded or none are
+pub async fn append(
+    db: &SqlitePool,
+    user: &str,
+    origin: Option<String>,
+    events: Vec<Event>,
+) -> Result<Vec<Change>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let mut changes = Vec::with_capacity(events.len());
+
+    for event in events {
+        let encoded = serde_json::to_string(&event).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+        let result = sqlx::query("INSERT INTO changes (user_id, origin, event) VALUES (?, ?, ?)")
+            .bind(user)
+            .bind(&origin)
+            .bind(encoded)
+            .execute(&mut *tx)
+            .await?;
+
+        changes.push(Change {
+            seq: result.last_insert_rowid() as u64,
+            origin: origin.clone(),
+            event,
+        });
+    }
+
+    tx.commit().await?;
+    Ok(changes)
+}
+
+/// A page of the user's journal after some cursor
+pub struct Page {
+    pub changes: Vec<Change>,
+    /// Newest sequence number the user has
+    pub latest: u64,
+    /// Cursors below this missed changes that have since been dropped
+    pub floor: u64,
+}
+
+/// Up to `limit` changes after `since`, read in one transaction so they agree with `latest`
+pub async fn changes_since(
+    db: &SqlitePool,
+    user: &str,
+    since: u64,
+    limit: u32,
+) -> Result<Page, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let rows = sqlx::query(
+        "SELECT seq, origin, event FROM changes WHERE user_id = ? AND seq > ? ORDER BY seq LIMIT ?",
+    )
+    .bind(user)
+    .bind(since as i64)
+    .bind(limit)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let latest: i64 = sqlx::query("SELECT COALESCE(MAX(seq), 0) FROM changes WHERE user_id = ?")
+        .bind(user)
+        .fetch_one(&mut *tx)
+        .await?
+        .get(0);
+
+    let floor: i64 = sqlx::query("SELECT COALESCE(MAX(seq), 0) FROM journal_floor WHERE user_id = ?")
+        .bind(user)
+        .fetch_one(&mut *tx)
+        .await?
+        .get(0);
+
+    tx.commit().await?;
+
+    Ok(Page {
+        changes: rows.into_iter().filter_map(to_change).collect(),
+        // Everything may have been dropped, the floor is then the newest number handed out
+        latest: latest.max(floor) as u64,
+        floor: floor as u64,
+    })
+}
+
+/// Drops changes a later one makes redundant, which every cursor can skip, then changes older than
+/// the retention period, raising the floor so cursors that still needed them get a full reconcile.
+pub async fn compact(db: &SqlitePool, user: &str) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let rows = sqlx::query("SELECT seq, origin, event FROM changes WHERE user_id = ? ORDER BY seq")
+        .bind(user)
+        .fetch_all(&mut *tx)
+        .await?;
+    let changes: Vec<Change> = rows.into_iter().filter_map(to_change).collect();
+    let events: Vec<Event> = changes.iter().map(|c| c.event.clone()).collect();
+
+    for (change, dropped) in changes.iter().zip(superseded(&events)) {
+        if dropped {
+            sqlx::query("DELETE FROM changes WHERE seq = ?")
+                .bind(change.seq as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+
+    let expired: Option<i64> = sqlx::query(
+        "SELECT MAX(seq) FROM changes WHERE user_id = ? AND accepted_at < unixepoch() - ?",
+    )
+    .bind(user)
+    .bind(RETENTION_SECS)
+    .fetch_one(&mut *tx)
+    .await?
+    .get(0);
+
+    if let Some(floor) = expired {
+        sqlx::query("DELETE FROM changes WHERE user_id = ? AND seq <= ?")
+            .bind(user)
+            .bind(floor)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO journal_floor (user_id, seq) VALUES (?, ?)
+             ON CONFLICT (user_id) DO UPDATE SET seq = excluded.seq",
+        )
+        .bind(user)
+        .bind(floor)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+/// Compacts every user's journal now and then, off the request path
+pub async fn compact_periodically(db: SqlitePool) {
+    let mut interval = tokio::time::interval(COMPACT_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let users = sqlx::query("SELECT DISTINCT user_id FROM changes")
+            .fetch_all(&db)
+            .await;
+        let Ok(users) = users else {
+            continue;
+        };
+
+        for row in users {
+            let user: String = row.get("user_id");
+            if let Err(e) = compact(&db, &user).await {
+                eprintln!("Failed to compact journal for {}: {}", user, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use axum::{
+        Extension,
+        extract::{Query, State},
+        http::{StatusCode, Uri},
+    };
+    use common::EventKind;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use tokio::sync::RwLock;
+
+    use super::*;
+    use crate::{auth, route_handlers::get_changes};
+
+    /// A fresh journal in memory, on one connection kept open since each would get its own database
+    async fn memory_db() -> SqlitePool {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&db).await.unwrap();
+        db
+    }
+
+    async fn modify(db: &SqlitePool, paths: &[&str]) {
+        let events = paths
+            .iter()
+            .map(|p| Event::new(EventKind::Modify { path: p.to_string() }))
+            .collect();
+        append(db, "alice", None, events).await.unwrap();
+    }
+
+    /// Backdates every change up to `seq` past the retention period
+    async fn expire(db: &SqlitePool, seq: i64) {
+        sqlx::query("UPDATE changes SET accepted_at = accepted_at - ? - 1 WHERE seq <= ?")
+            .bind(RETENTION_SECS)
+            .bind(seq)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    async fn status_since(db: &SqlitePool, since: u64) -> StatusCode {
+        let state = Arc::new(crate::Data {
+            db: db.clone(),
+            clients: Arc::new(RwLock::new(HashMap::new())),
+        });
+        let user = auth::AuthUser(auth::Data {
+            user: "alice".to_string(),
+            admin: false,
+            exp: 0,
+        });
+        let uri: Uri = format!("/changes?since={}", since).parse().unwrap();
+        let query = Query::try_from_uri(&uri).unwrap();
+
+        get_changes(State(state), Extension(user), query).await.status()
+    }
+
+    #[tokio::test]
+    async fn retention_raises_the_floor() {
+        let db = memory_db().await;
+        modify(&db, &["a.txt", "b.txt", "c.txt"]).await;
+        expire(&db, 2).await;
+
+        compact(&db, "alice").await.unwrap();
+        let page = changes_since(&db, "alice", 0, 100).await.unwrap();
+        assert_eq!(page.floor, 2);
+        assert_eq!(page.latest, 3);
+        let seqs: Vec<u64> = page.changes.iter().map(|c| c.seq).collect();
+        assert_eq!(seqs, [3]);
+    }
+
+    #[tokio::test]
+    async fn cursor_below_the_floor_is_gone() {
+        let db = memory_db().await;
+        modify(&db, &["a.txt", "b.txt", "c.txt"]).await;
+        expire(&db, 2).await;
+        compact(&db, "alice").await.unwrap();
+
+        assert_eq!(status_since(&db, 1).await, StatusCode::GONE);
+        assert_eq!(status_since(&db, 2).await, StatusCode::OK);
+        assert_eq!(status_since(&db, 4).await, StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn latest_holds_when_superseded_rows_are_dropped() {
+        let db = memory_db().await;
+        modify(&db, &["a.txt", "b.txt", "a.txt"]).await;
+
+        compact(&db, "alice").await.unwrap();
+        let page = changes_since(&db, "alice", 0, 100).await.unwrap();
+        let seqs: Vec<u64> = page.changes.iter().map(|c| c.seq).collect();
+        assert_eq!(seqs, [2, 3]);
+        assert_eq!(page.latest, 3);
+        assert_eq!(page.floor, 0);
+        // A cursor on a dropped row is still served, nothing after it is missing
+        assert_eq!(status_since(&db, 1).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn latest_stays_at_the_floor_once_everything_expired() {
+        let db = memory_db().await;
+        modify(&db, &["a.txt", "b.txt"]).await;
+        expire(&db, 2).await;
+
+        compact(&db, "alice").await.unwrap();
+        let page = changes_since(&db, "alice", 0, 100).await.unwrap();
+        assert!(page.changes.is_empty());
+        assert_eq!((page.floor, page.latest), (2, 2));
+        assert_eq!(status_since(&db, 2).await, StatusCode::OK);
+    }
+}
@@ -1,34 +1,66 @@
 use axum::{
-    Extension, Router, extract::{DefaultBodyLimit, ws::{Message, WebSocket, WebSocketUpgrade}}, http::StatusCode, response::Response, routing::{delete, get, post, put}
+    Extension, Router, extract::{DefaultBodyLimit, State, ws::{Message, WebSocket, WebSocketUpgrade}}, http::StatusCode, middleware, response::Response, routing::{delete, get, post, put}
 };
+use common::Notification;
 use futures_util::{SinkExt, StreamExt};
-use std::{
-    collections::HashMap,
-    sync::{Arc, atomic::AtomicBool},
-};
+use sqlx::SqlitePool;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 use tokio::{
     net::TcpListener,
     sync::{RwLock, mpsc},
 };
 
-use crate::route_handlers::{delete_file, get_file, get_logs, post_file, post_logs, rename_file};
+use crate::{
+    auth::{AuthUser, auth_middleware},
+    delta::{DELTA_BODY_LIMIT, get_signature, post_delta},
+    journal::compact_periodically,
+    route_handlers::{
+        create_folder, delete_file, get_changes, get_file, get_logs, get_manifest, post_file, post_logs,
+        rename_file, SYNC_PATH,
+    },
+    uploads::{CHUNK_SIZE, commit_upload, create_upload, get_upload, put_chunk},
+};
 
+pub mod auth;
+pub mod delta;
+pub mod journal;
+pub mod manifest;
 pub mod route_handlers;
-pub mod util;
+pub mod uploads;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Connected sockets by id, along with the user each one belongs to
+pub type Clients = Arc<RwLock<HashMap<i32, (String, mpsc::UnboundedSender<Message>)>>>;
+
+pub type AppState = Arc<Data>;
 
-pub type Clients = Arc<RwLock<HashMap<i32, mpsc::UnboundedSender<Message>>>>;
+pub struct Data {
+    /// Change journal, see `journal`
+    pub db: SqlitePool,
+    pub clients: Clients,
+}
 
-pub async fn handle_ws(ws: WebSocketUpgrade, clients: Clients) -> Response {
-    ws.on_upgrade(|socket| client_loop(socket, clients))
+pub async fn handle_ws(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let clients = state.clients.clone();
+    ws.on_upgrade(|socket| client_loop(socket, user.0.user, clients))
 }
 
-async fn client_loop(socket: WebSocket, clients: Clients) {
+async fn client_loop(socket: WebSocket, user: String, clients: Clients) {
     let (mut ws_sender, mut ws_receiver) = socket.split();
     let (sender, mut receiver) = mpsc::unbounded_channel::<Message>();
 
     let client_id = (rand::random::<u32>() as i32).abs();
-    clients.write().await.insert(client_id, sender.clone());
-    println!("Adding client: {}", client_id);
+    clients
+        .write()
+        .await
+        .insert(client_id, (user.clone(), sender.clone()));
+    println!("Adding client: {} for {}", client_id, user);
 
     tokio::spawn(async move {
         while let Some(msg) = receiver.recv().await {
@@ -38,25 +70,41 @@ async fn client_loop(socket: WebSocket, clients: Clients) {
         }
     });
 
-    while let Some(Ok(Message::Text(text))) = ws_receiver.next().await {
-        println!("{}", text);
-        broadcast_clients(&clients, client_id).await;
+    // Pings keep idle connections open through proxies, silence past the timeout means it's dead
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        tokio::select! {
+            msg = tokio::time::timeout(HEARTBEAT_TIMEOUT, ws_receiver.next()) => match msg {
+                Ok(Some(Ok(Message::Close(_)))) | Ok(Some(Err(_))) | Ok(None) | Err(_) => break,
+                // Pongs and anything else just show the client is still there
+                Ok(Some(Ok(_))) => (),
+            },
+            _ = heartbeat.tick() => {
+                if sender.send(Message::Ping(Default::default())).is_err() {
+                    break;
+                }
+            }
+        }
     }
 
     clients
         .write()
         .await
-        .retain(|_id, client_tx| !client_tx.same_channel(&sender));
+        .retain(|_id, (_, client_tx)| !client_tx.same_channel(&sender));
     println!("Client disconnected");
 }
 
-async fn broadcast_clients(clients: &Clients, id: i32) {
+/// Pushes newly accepted changes to every device `user` has connected
+pub async fn broadcast_clients(clients: &Clients, user: &str, notification: &Notification) {
     let clients_guard = clients.read().await;
 
-    let msg = Message::Text("Updated".into());
+    let Ok(text) = serde_json::to_string(notification) else {
+        return;
+    };
+    let msg = Message::Text(text.into());
 
-    for (client_id, client_tx) in clients_guard.iter() {
-        if *client_id != id {
+    for (client_user, client_tx) in clients_guard.values() {
+        if client_user == user {
             let _ = client_tx.send(msg.clone());
         }
     }
@@ -64,26 +112,47 @@ async fn broadcast_clients(clients: &Clients, id: i32) {
 
 #[tokio::main]
 async fn main() {
-    let clients: Clients = Arc::new(RwLock::new(HashMap::new()));
-    let is_running = Arc::new(AtomicBool::new(false));
-    let copy = is_running.clone();
-    let path = "logs.toml";
+    let _ = std::fs::create_dir_all(SYNC_PATH);
+    let db = journal::open(&format!("sqlite://{}/journal.db", SYNC_PATH)).await;
+    let db = match db {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Failed to open the change journal: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(compact_periodically(db.clone()));
+
+    let state: AppState = Arc::new(Data {
+        db,
+        clients: Arc::new(RwLock::new(HashMap::new())),
+    });
     let app = Router::new()
+        .route("/ws", get(handle_ws))
+        .route("/logs", post(post_logs))
+        .route("/logs", get(get_logs))
+        .route("/changes", get(get_changes))
+        .route("/manifest", get(get_manifest))
+        .route("/files/{*path}", get(get_file))
+        .route("/files/{*path}", post(post_file).layer(DefaultBodyLimit::max(50 * 1024 * 1024)))
+        .route("/files/{*path}", delete(delete_file))
+        .route("/files", put(rename_file))
+        .route("/folders/{*path}", post(create_folder))
+        .route("/uploads", post(create_upload))
+        .route("/uploads/{id}", get(get_upload))
+        .route(
+            "/uploads/{id}",
+            put(put_chunk).layer(DefaultBodyLimit::max(CHUNK_SIZE as usize + 1024)),
+        )
+        .route("/uploads/{id}/commit", post(commit_upload))
+        .route("/signatures/{*path}", get(get_signature))
         .route(
-            "/ws",
-            get({
-                let clients = clients.clone();
-                move |ws: WebSocketUpgrade| handle_ws(ws, clients)
-            }),
+            "/delta/{*path}",
+            post(post_delta).layer(DefaultBodyLimit::max(DELTA_BODY_LIMIT)),
         )
-        .layer(Extension(clients))
+        .layer(middleware::from_fn(auth_middleware))
         .route("/health", get(StatusCode::OK))
-        .route("/logs", post(move |value| post_logs(is_running, value)))
-        .route("/logs", get(move || get_logs(path, copy)))
-        .route("/files/{*path}", get(get_file))
-        .route("/files/{*path}", post(post_file).layer(DefaultBodyLimit::max(50 * 1024 * 1014)))
-        .route("/files/{*path}", delete(delete_file))
-        .route("/files", put(rename_file));
+        .with_state(state);
     
     let listener = TcpListener::bind("0.0.0.0:3000")
         .await
@@ -0,0 +1,33 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use common::manifest::hash_file;
+use once_cell::sync::Lazy;
+
+type ServedHashes = HashMap<PathBuf, (u64, i64, String)>;
+
+/// Hashes of files served recently, valid while their size and mtime stay the same
+static SERVED_HASHES: Lazy<Mutex<ServedHashes>> = Lazy::new(|| Mutex::new(HashMap::new()));
+const SERVED_HASHES_KEPT: usize = 10_000;
+
+/// Hash of a file about to be served, only read again once it has changed
+pub fn served_hash(path: &Path, size: u64, mtime: i64) -> io::Result<String> {
+    if let Some((s, m, hash)) = SERVED_HASHES.lock().unwrap().get(path)
+        && *s == size
+        && *m == mtime
+    {
+        return Ok(hash.clone());
+    }
+
+    let hash = hash_file(path)?;
+    let mut hashes = SERVED_HASHES.lock().unwrap();
+    if hashes.len() >= SERVED_HASHES_KEPT {
+        hashes.clear();
+    }
+    hashes.insert(path.to_path_buf(), (size, mtime, hash.clone()));
+    Ok(hash)
+}
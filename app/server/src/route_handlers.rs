@@ -1,49 +1,180 @@
 use std::{
-    fs::OpenOptions,
-    io::Write,
-    sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
-    },
+    io::SeekFrom,
+    path::{Component, PathBuf},
+    time::UNIX_EPOCH,
 };
 
 use axum::{
     Json,
-    body::Bytes,
-    extract::Path,
-    http::StatusCode,
+    body::{Body, Bytes},
+    Extension,
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderName, StatusCode, header},
     response::{IntoResponse, Response},
 };
 
+use common::{
+    ChangeFeed, Envelope, Notification, PROTOCOL_VERSION,
+    manifest::{Manifest, build_manifest, hash_file},
+};
 use once_cell::sync::Lazy;
+use serde::Deserialize;
 use serde_json::{Value, json};
-use tokio::fs;
-
-use crate::util::{Events, clean_logs, get_events};
-static STORAGE_PATH: Lazy<String> = Lazy::new(|| "Storage".to_string());
-pub async fn get_logs(path: &str, is_running: Arc<AtomicBool>) -> Response {
-    while is_running.load(Ordering::Relaxed) {}
-    let events = get_events(path);
-    if events.is_some() {
-        (StatusCode::OK, Json(events.unwrap())).into_response()
-    } else {
-        (StatusCode::NO_CONTENT).into_response()
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    AppState,
+    auth::AuthUser,
+    broadcast_clients,
+    journal::{self, all_changes, changes_since},
+    manifest::served_hash,
+};
+/// Same root as the website backend's `STORAGE_ROOT`, so both see each user's files in one place
+pub static STORAGE_PATH: Lazy<String> = Lazy::new(|| {
+    dotenv::dotenv().ok();
+    std::env::var("STORAGE_ROOT").unwrap_or_else(|_| "Storage".to_string())
+});
+pub static SYNC_PATH: &str = "Sync";
+/// Most changes returned by one `/changes` call, the client keeps asking while there are `more`
+const CHANGES_PAGE: u32 = 1000;
+
+/// Root of everything `user` has synced, the backend's `get_user_path`
+pub fn user_storage(user: &str) -> PathBuf {
+    PathBuf::from(STORAGE_PATH.as_str()).join(user)
+}
+
+/// Server side bookkeeping for `user`: event log, manifest cache and partial uploads
+pub fn user_sync_dir(user: &str) -> PathBuf {
+    PathBuf::from(SYNC_PATH).join(user)
+}
+
+/// Resolves a client supplied path inside the user's storage, refusing anything that could escape it
+pub fn user_path(user: &str, path: &str) -> Option<PathBuf> {
+    let mut target = user_storage(user);
+
+    for component in std::path::Path::new(path).components() {
+        match component {
+            Component::Normal(part) => target.push(part),
+            _ => return None,
+        }
+    }
+
+    Some(target)
+}
+
+fn journal_error() -> Response {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"status":"error", "message":"Change journal unavailable"})),
+    )
+        .into_response()
+}
+
+pub async fn get_logs(
+    State(state): State<AppState>,
+    Extension(AuthUser(user)): Extension<AuthUser>,
+) -> Response {
+    let Ok(changes) = all_changes(&state.db, &user.user).await else {
+        return journal_error();
+    };
+    if changes.is_empty() {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+
+    let events = changes.into_iter().map(|c| c.event).collect();
+    (StatusCode::OK, Json(Envelope::new(events))).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ChangesQuery {
+    #[serde(default)]
+    since: u64,
+}
+
+/// Changes accepted after the client's cursor. A cursor the journal can't serve, because the
+/// changes after it were dropped or the journal was reset, gets 410 with the cursor to start over
+/// from once the client has reconciled in full.
+pub async fn get_changes(
+    State(state): State<AppState>,
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    Query(query): Query<ChangesQuery>,
+) -> Response {
+    let Ok(page) = changes_since(&state.db, &user.user, query.since, CHANGES_PAGE).await else {
+        return journal_error();
+    };
+
+    if query.since < page.floor || query.since > page.latest {
+        let feed = ChangeFeed {
+            version: PROTOCOL_VERSION,
+            changes: Vec::new(),
+            cursor: page.latest,
+            more: false,
+        };
+        return (StatusCode::GONE, Json(feed)).into_response();
+    }
+
+    let cursor = page.changes.last().map_or(page.latest, |c| c.seq);
+    let feed = ChangeFeed {
+        version: PROTOCOL_VERSION,
+        changes: page.changes,
+        cursor,
+        more: cursor < page.latest,
+    };
+    (StatusCode::OK, Json(feed)).into_response()
+}
+
+pub async fn get_manifest(Extension(AuthUser(user)): Extension<AuthUser>) -> Response {
+    let manifest = tokio::task::spawn_blocking(move || {
+        let cache_path = user_sync_dir(&user.user).join("manifest.json");
+        let cache = Manifest::load(&cache_path);
+        let mut manifest = build_manifest(&user_storage(&user.user), &cache, |_, _| false);
+        let _ = std::fs::create_dir_all(user_sync_dir(&user.user));
+        manifest.save(&cache_path);
+
+        // Inodes only mean something on the disk they came from, devices track their own
+        for entry in manifest.files.values_mut() {
+            entry.inode = 0;
+        }
+        manifest
+    })
+    .await;
+
+    match manifest {
+        Ok(manifest) => (StatusCode::OK, Json(manifest)).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     }
 }
 
-pub async fn post_logs(is_running: Arc<AtomicBool>, Json(events): Json<Events>) -> Response {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("logs.toml")
-        .unwrap();
-    for e in events.events {
-        let _ = file.write(e.print().as_bytes());
+/// Numbers events into the user's journal and tells their connected devices to pull. Events come from
+/// desktop clients and from the website backend when files change there.
+pub async fn post_logs(
+    State(state): State<AppState>,
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    headers: HeaderMap,
+    Json(envelope): Json<Envelope>,
+) -> Response {
+    if !envelope.is_supported() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status":"error", "message":"Unsupported protocol version"})),
+        )
+            .into_response();
     }
-    is_running.store(true, Ordering::Relaxed);
-    clean_logs("logs.toml");
-    tokio::time::sleep(std::time::Duration::from_millis(3000)).await;
-    is_running.store(false, Ordering::Relaxed);
+
+    // Lets each device skip its own changes when it reads them back from `/changes`
+    let origin = headers
+        .get("X-Device-Id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let Ok(changes) = journal::append(&state.db, &user.user, origin, envelope.events).await else {
+        return journal_error();
+    };
+    broadcast_clients(&state.clients, &user.user, &Notification::new(changes)).await;
 
     (
         StatusCode::OK,
@@ -52,14 +183,148 @@ pub async fn post_logs(is_running: Arc<AtomicBool>, Json(events): Json<Events>)
         .into_response()
 }
 
-pub async fn get_file(Path(file_path): Path<String>) -> Response{
-    let file_path = format!("{}/{}", &STORAGE_PATH.as_str(), file_path);
-    let data = fs::read(file_path).await.unwrap();
-    (StatusCode::OK, data).into_response()
+/// What a `Range` header asks of a file of a given size
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No range, or one this server doesn't serve like several at once, answered with the whole file
+    Whole,
+    /// First and last byte, inclusive
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=start-[end]` or suffix `bytes=-length` range
+fn parse_range(value: &str, size: u64) -> ByteRange {
+    let Some((start, end)) = value
+        .strip_prefix("bytes=")
+        .filter(|v| !v.contains(','))
+        .and_then(|v| v.trim().split_once('-'))
+    else {
+        return ByteRange::Whole;
+    };
+
+    if start.is_empty() {
+        return match end.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if size == 0 => ByteRange::Unsatisfiable,
+            Ok(length) => ByteRange::Part(size.saturating_sub(length), size - 1),
+            Err(_) => ByteRange::Whole,
+        };
+    }
+
+    let Ok(start) = start.parse::<u64>() else {
+        return ByteRange::Whole;
+    };
+    let end = match end {
+        "" => u64::MAX,
+        end => match end.parse::<u64>() {
+            Ok(end) if end >= start => end,
+            _ => return ByteRange::Whole,
+        },
+    };
+
+    if start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Part(start, end.min(size - 1))
+}
+
+/// Streams a stored file, honouring a single `Range` so interrupted downloads can resume.
+/// `If-Range` carries the ETag the client started from; if the file changed since, it gets all of it.
+/// `X-Content-Hash` is the SHA-256 of the whole file either way, for the client to check it against.
+pub async fn get_file(
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    Path(file_path): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(file_path) = user_path(&user.user, &file_path) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let meta = match fs::metadata(&file_path).await {
+        Ok(meta) if meta.is_file() => meta,
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let size = meta.len();
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let etag = format!("\"{}-{}\"", size, mtime);
+
+    let hash_path = file_path.clone();
+    let hash =
+        tokio::task::spawn_blocking(move || served_hash(&hash_path, size, mtime as i64)).await;
+    let Ok(Ok(hash)) = hash else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| {
+            headers
+                .get(header::IF_RANGE)
+                .is_none_or(|v| v.as_bytes() == etag.as_bytes())
+        })
+        .map_or(ByteRange::Whole, |v| parse_range(v, size));
+
+    let Ok(mut file) = fs::File::open(&file_path).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let (start, end) = match range {
+        ByteRange::Part(start, end) => (start, end),
+        ByteRange::Whole => {
+            return (
+                StatusCode::OK,
+                [
+                    (header::CONTENT_LENGTH, size.to_string()),
+                    (header::ACCEPT_RANGES, "bytes".to_string()),
+                    (header::ETAG, etag),
+                    (HeaderName::from_static("x-content-hash"), hash),
+                ],
+                Body::from_stream(ReaderStream::new(file)),
+            )
+                .into_response();
+        }
+        ByteRange::Unsatisfiable => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+            )
+                .into_response();
+        }
+    };
+
+    if file.seek(SeekFrom::Start(start)).await.is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    let length = end - start + 1;
+    (
+        StatusCode::PARTIAL_CONTENT,
+        [
+            (header::CONTENT_LENGTH, length.to_string()),
+            (header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size)),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+            (header::ETAG, etag),
+            (HeaderName::from_static("x-content-hash"), hash),
+        ],
+        Body::from_stream(ReaderStream::new(file.take(length))),
+    )
+        .into_response()
 }
 
-pub async fn delete_file(Path(file_path): Path<String>) -> Response {
-    let file_path = format!("{}/{}", &STORAGE_PATH.as_str(), file_path);
+pub async fn delete_file(
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    Path(file_path): Path<String>,
+) -> Response {
+    let Some(file_path) = user_path(&user.user, &file_path) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
     let meta = tokio::fs::metadata(&file_path).await;
     if meta.is_err() {
        return StatusCode::NOT_FOUND.into_response()
@@ -75,34 +340,146 @@ pub async fn delete_file(Path(file_path): Path<String>) -> Response {
     StatusCode::OK.into_response()
 }
 
-pub async fn rename_file(Json(payload): Json<Value>) {
-    let old = payload.get("from").unwrap().as_str().unwrap();
-    let new = payload.get("to").unwrap().as_str().unwrap();
-    let old = format!("{}/{}", &STORAGE_PATH.as_str(), old);
-    let new = format!("{}/{}", &STORAGE_PATH.as_str(), new);
-    let _ = fs::rename(&old, &new);
+pub async fn rename_file(
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    Json(payload): Json<Value>,
+) -> StatusCode {
+    let (Some(old), Some(new)) = (
+        payload.get("from").and_then(Value::as_str),
+        payload.get("to").and_then(Value::as_str),
+    ) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let (Some(old), Some(new)) = (user_path(&user.user, old), user_path(&user.user, new)) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    if fs::metadata(&old).await.is_err() {
+        return StatusCode::NOT_FOUND;
+    }
+
+    // The client only moves its own copy once the server has, a rename that didn't happen can't
+    // be reported as done
+    if let Some(parent) = new.parent()
+        && fs::create_dir_all(parent).await.is_err()
+    {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    match fs::rename(&old, &new).await {
+        Ok(()) => StatusCode::OK,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn create_folder(
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    Path(folder_path): Path<String>,
+) -> StatusCode {
+    let Some(folder_path) = user_path(&user.user, &folder_path) else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    match fs::create_dir_all(&folder_path).await {
+        Ok(_) => StatusCode::OK,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Returns true when the stored file no longer matches the version the client last synced, its
+/// `base_hash` (empty when the client expects the file not to exist yet).
+pub async fn has_conflict(file_path: &std::path::Path, base_hash: Option<&str>) -> bool {
+    let Some(base_hash) = base_hash else {
+        return false;
+    };
+
+    let path = file_path.to_path_buf();
+    let current = tokio::task::spawn_blocking(move || hash_file(&path).ok())
+        .await
+        .unwrap_or(None);
+
+    match current {
+        Some(current) => current != base_hash,
+        None => false,
+    }
+}
+
+pub async fn post_file(
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    Path(file_path): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some(file_path) = user_path(&user.user, &file_path) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let base_hash = headers.get("X-Base-Hash").and_then(|v| v.to_str().ok());
+    if has_conflict(&file_path, base_hash).await {
+        return StatusCode::CONFLICT.into_response();
+    }
+
+    let Some(dir) = file_path.parent() else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    if tokio::fs::create_dir_all(dir).await.is_err()
+        || tokio::fs::write(&file_path, body).await.is_err()
+    {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    StatusCode::OK.into_response()
 }
 
-pub async fn post_file(Path(file_path): Path<String>, body: Bytes) {
-    let mut file_path = file_path;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if file_path.contains("$-$") {
-        let parts: Vec<_> = file_path.split("$-$").collect();
-        let old_name = format!("{}/{}", STORAGE_PATH.as_str(), parts[0]);
-        let new_name = format!("{}/{}", STORAGE_PATH.as_str(), parts[1]);
+    #[test]
+    fn user_paths_stay_in_the_users_storage() {
+        assert_eq!(
+            user_path("alice", "docs/a.txt"),
+            Some(user_storage("alice").join("docs").join("a.txt"))
+        );
+    }
 
-        if tokio::fs::metadata(&new_name).await.is_ok() {
-            file_path = parts[1].to_string();
-        } else if tokio::fs::metadata(&old_name).await.is_ok() {
-            file_path = parts[0].to_string();
-        } else {
-            file_path = parts[1].to_string();
+    #[test]
+    fn escaping_paths_are_refused() {
+        for path in ["..", "../bob/a.txt", "docs/../../bob", "/etc/passwd"] {
+            assert_eq!(user_path("alice", path), None, "{}", path);
         }
     }
 
-    let file_path = format!("{}/{}", STORAGE_PATH.as_str(), file_path);
-    let dir = std::path::Path::new(&file_path).parent().unwrap();
+    #[cfg(windows)]
+    #[test]
+    fn prefixed_paths_are_refused() {
+        assert_eq!(user_path("alice", "C:\\Windows"), None);
+        assert_eq!(user_path("alice", "\\\\server\\share"), None);
+    }
+
+    #[test]
+    fn single_ranges_are_clamped_to_the_file() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Part(0, 99));
+        assert_eq!(parse_range("bytes=500-", 1000), ByteRange::Part(500, 999));
+        assert_eq!(parse_range("bytes=900-5000", 1000), ByteRange::Part(900, 999));
+    }
 
-    tokio::fs::create_dir_all(dir).await.unwrap();
-    tokio::fs::write(&file_path, body).await.unwrap();
+    #[test]
+    fn suffix_range_is_the_end_of_the_file() {
+        assert_eq!(parse_range("bytes=-500", 1000), ByteRange::Part(500, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000), ByteRange::Part(0, 999));
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn unsupported_ranges_get_the_whole_file() {
+        assert_eq!(parse_range("bytes=0-9,20-29", 1000), ByteRange::Whole);
+        assert_eq!(parse_range("items=0-9", 1000), ByteRange::Whole);
+        assert_eq!(parse_range("bytes=abc-", 1000), ByteRange::Whole);
+        assert_eq!(parse_range("bytes=50-10", 1000), ByteRange::Whole);
+    }
+
+    #[test]
+    fn range_past_the_end_is_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
 }
@@ -0,0 +1,229 @@
+use std::{
+    io,
+    path::{Path as FsPath, PathBuf},
+};
+
+use axum::{
+    Extension, Json,
+    body::Bytes,
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use common::manifest::hash_file;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::{
+    auth::AuthUser,
+    route_handlers::{has_conflict, user_path, user_sync_dir},
+};
+
+pub const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Where `user`'s partial uploads are kept
+pub fn uploads_dir(user: &str) -> PathBuf {
+    user_sync_dir(user).join("Uploads")
+}
+
+/// Moves a finished upload from the uploads folder into the user's storage. `STORAGE_ROOT` can be
+/// on another filesystem than the server's own folder, where it's copied next to `to`, flushed and
+/// renamed over it instead, so readers still never see it half written.
+pub fn move_into_place(from: &FsPath, to: &FsPath) -> io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => (),
+        result => return result,
+    }
+
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let temp = to.with_file_name(format!(".{}.{:016x}.tmp", name, rand::random::<u64>()));
+    let copied = std::fs::copy(from, &temp)
+        .and_then(|_| std::fs::File::open(&temp)?.sync_all())
+        .and_then(|_| std::fs::rename(&temp, to));
+    if copied.is_err() {
+        let _ = std::fs::remove_file(&temp);
+        return copied;
+    }
+
+    std::fs::remove_file(from)
+}
+
+/// An upload in progress, kept next to its partial data so it survives restarts.
+#[derive(Serialize, Deserialize)]
+pub struct UploadSession {
+    pub path: String,
+    pub size: u64,
+    pub hash: Option<String>,
+    pub base_hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChunkQuery {
+    offset: u64,
+}
+
+fn data_path(user: &str, id: &str) -> PathBuf {
+    uploads_dir(user).join(format!("{}.part", id))
+}
+
+fn session_path(user: &str, id: &str) -> PathBuf {
+    uploads_dir(user).join(format!("{}.json", id))
+}
+
+async fn load_session(user: &str, id: &str) -> Option<UploadSession> {
+    // Ids are generated here, anything else could escape the uploads folder
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let content = fs::read_to_string(session_path(user, id)).await.ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+async fn received(user: &str, id: &str) -> u64 {
+    fs::metadata(data_path(user, id))
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0)
+}
+
+async fn remove_session(user: &str, id: &str) {
+    let _ = fs::remove_file(data_path(user, id)).await;
+    let _ = fs::remove_file(session_path(user, id)).await;
+}
+
+pub async fn create_upload(
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    Json(session): Json<UploadSession>,
+) -> Response {
+    if user_path(&user.user, &session.path).is_none() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let user = user.user;
+    let id = format!("{:016x}", rand::random::<u64>());
+
+    let saved = async {
+        fs::create_dir_all(uploads_dir(&user)).await?;
+        fs::write(data_path(&user, &id), b"").await?;
+        fs::write(session_path(&user, &id), serde_json::to_string(&session)?).await
+    };
+
+    if saved.await.is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({"id": id, "offset": 0, "chunk_size": CHUNK_SIZE})),
+    )
+        .into_response()
+}
+
+pub async fn get_upload(
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    Path(id): Path<String>,
+) -> Response {
+    let user = user.user;
+    if load_session(&user, &id).await.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({"offset": received(&user, &id).await})),
+    )
+        .into_response()
+}
+
+pub async fn put_chunk(
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    Path(id): Path<String>,
+    Query(query): Query<ChunkQuery>,
+    body: Bytes,
+) -> Response {
+    let user = user.user;
+    let Some(session) = load_session(&user, &id).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // Chunks must arrive in order, tell the client where to resume from
+    let offset = received(&user, &id).await;
+    if query.offset != offset {
+        return (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            Json(json!({"offset": offset})),
+        )
+            .into_response();
+    }
+
+    if offset + body.len() as u64 > session.size {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let file = fs::OpenOptions::new()
+        .append(true)
+        .open(data_path(&user, &id))
+        .await;
+    let Ok(mut file) = file else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    if file.write_all(&body).await.is_err() || file.flush().await.is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({"offset": offset + body.len() as u64})),
+    )
+        .into_response()
+}
+
+pub async fn commit_upload(
+    Extension(AuthUser(user)): Extension<AuthUser>,
+    Path(id): Path<String>,
+) -> Response {
+    let user = user.user;
+    let Some(session) = load_session(&user, &id).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    if received(&user, &id).await != session.size {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    if let Some(hash) = &session.hash {
+        let path = data_path(&user, &id);
+        let actual = tokio::task::spawn_blocking(move || hash_file(&path).ok())
+            .await
+            .unwrap_or(None);
+
+        if actual.as_ref() != Some(hash) {
+            remove_session(&user, &id).await;
+            return StatusCode::UNPROCESSABLE_ENTITY.into_response();
+        }
+    }
+
+    let Some(file_path) = user_path(&user, &session.path) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    if has_conflict(&file_path, session.base_hash.as_deref()).await {
+        remove_session(&user, &id).await;
+        return StatusCode::CONFLICT.into_response();
+    }
+
+    let dir = file_path.parent().unwrap();
+    if fs::create_dir_all(dir).await.is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    let data = data_path(&user, &id);
+    let moved = tokio::task::spawn_blocking(move || move_into_place(&data, &file_path)).await;
+    if !matches!(moved, Ok(Ok(()))) {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    remove_session(&user, &id).await;
+    StatusCode::OK.into_response()
+}
@@ -0,0 +1,214 @@
+use std::{env, fs, path::PathBuf};
+
+use common::config::{config_path, take_config_arg};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{colour::Colour, println};
+
+/// Written by the desktop client next to its config while it's running
+#[derive(Deserialize)]
+struct ControlFile {
+    port: u16,
+    token: String,
+}
+
+/// Next to the client's config, found from `--config`, `DRIVE_SYNC_CONFIG` or the config folder
+/// just like the client does. `DSYNC_CONTROL` points straight at a control file instead.
+fn control_file_path() -> PathBuf {
+    if let Ok(path) = env::var("DSYNC_CONTROL") {
+        return PathBuf::from(path);
+    }
+
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    common::config::control_file_path(&config_path(take_config_arg(&mut args)))
+}
+
+/// Calls the running sync client's control API, printing why not if it can't be reached
+fn request(method: &str, path: &str, body: Option<Value>) -> Option<Value> {
+    let control = fs::read_to_string(control_file_path())
+        .ok()
+        .and_then(|data| serde_json::from_str::<ControlFile>(&data).ok());
+    let Some(control) = control else {
+        println!(Colour::Red, "The sync client isn't running.");
+        return None;
+    };
+
+    let url = format!("http://127.0.0.1:{}/{}", control.port, path);
+    let client = reqwest::blocking::Client::new();
+    let mut request = match method {
+        "POST" => client.post(&url),
+        _ => client.get(&url),
+    }
+    .bearer_auth(&control.token);
+    if let Some(body) = body {
+        request = request.json(&body);
+    }
+
+    let response = match request.send() {
+        Ok(response) => response,
+        Err(_) => {
+            println!(Colour::Red, "The sync client isn't running.");
+            return None;
+        }
+    };
+
+    if !response.status().is_success() {
+        println!(Colour::Red, "Request failed with status: {}", response.status());
+        return None;
+    }
+
+    Some(response.json().unwrap_or(Value::Null))
+}
+
+pub fn status() {
+    let Some(status) = request("GET", "status", None) else {
+        return;
+    };
+
+    let flag = |key: &str| status.get(key).and_then(Value::as_bool).unwrap_or(false);
+    let state = if flag("paused") {
+        "paused"
+    } else if flag("syncing") {
+        "syncing"
+    } else {
+        "idle"
+    };
+    println!(Colour::Green, "Sync client is {}", state);
+    println!(
+        Colour::Cyan,
+        "Server: {}",
+        if flag("connected") { "connected" } else { "disconnected" }
+    );
+
+    for folder in status["folders"].as_array().into_iter().flatten() {
+        let last_synced = folder["status"]["last_synced"].as_str().unwrap_or("never");
+        println!(
+            Colour::Cyan,
+            "{} -> /{}  {} pending, {} retrying, {} given up, last synced {}",
+            folder["local"].as_str().unwrap_or_default(),
+            folder["remote"].as_str().unwrap_or_default(),
+            folder["pending"].as_u64().unwrap_or(0),
+            folder["retrying"].as_u64().unwrap_or(0),
+            folder["dead_letters"].as_u64().unwrap_or(0),
+            last_synced
+        );
+    }
+
+    let errors = status["errors"].as_u64().unwrap_or(0);
+    if errors > 0 {
+        println!(Colour::Red, "{} recent errors, see 'dsync errors'", errors);
+    }
+}
+
+pub fn queue() {
+    let Some(queue) = request("GET", "queue", None) else {
+        return;
+    };
+
+    for folder in queue["folders"].as_array().into_iter().flatten() {
+        let events = folder["events"].as_array().cloned().unwrap_or_default();
+        println!(
+            Colour::Green,
+            "{} ({} pending)",
+            folder["local"].as_str().unwrap_or_default(),
+            events.len()
+        );
+
+        for event in events {
+            println!(Colour::Cyan, "{}", describe(&event));
+        }
+
+        for retry in folder["retrying"].as_array().into_iter().flatten() {
+            println!(
+                Colour::Cyan,
+                "{}  (retrying, {} failed attempts)",
+                describe(&retry["event"]),
+                retry["attempts"].as_u64().unwrap_or(0)
+            );
+        }
+
+        for dead in folder["dead_letters"].as_array().into_iter().flatten() {
+            println!(
+                Colour::Red,
+                "{}  (given up after {} attempts)",
+                describe(&dead["event"]),
+                dead["attempts"].as_u64().unwrap_or(0)
+            );
+        }
+    }
+}
+
+/// `modify     a.txt` or `rename     a.txt -> b.txt`
+fn describe(event: &Value) -> String {
+    let kind = event["kind"].as_str().unwrap_or_default();
+    let path = match (event["from"].as_str(), event["to"].as_str()) {
+        (Some(from), Some(to)) => format!("{} -> {}", from, to),
+        _ => event["path"].as_str().unwrap_or_default().to_string(),
+    };
+    format!("{:<10} {}", kind, path)
+}
+
+pub fn errors() {
+    let Some(errors) = request("GET", "errors", None) else {
+        return;
+    };
+
+    let errors = errors["errors"].as_array().cloned().unwrap_or_default();
+    if errors.is_empty() {
+        println!(Colour::Green, "No recent errors.");
+        return;
+    }
+
+    for error in errors {
+        let detail = [&error["detail"], &error["response"]]
+            .iter()
+            .filter_map(|value| value.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!(
+            Colour::Red,
+            "#{} {} {} {} {}",
+            error["id"].as_u64().unwrap_or_default(),
+            error["time"].as_str().unwrap_or_default(),
+            error["operation"].as_str().unwrap_or_default(),
+            error["path"].as_str().unwrap_or_default(),
+            detail
+        );
+    }
+}
+
+pub fn retry(id: u64) {
+    if request("POST", "retry", Some(serde_json::json!({ "id": id }))).is_some() {
+        println!(Colour::Green, "Retrying on the next sync.");
+    }
+}
+
+/// `pause`, `resume` or `sync`
+pub fn command(name: &str, done: &str) {
+    if request("POST", name, None).is_some() {
+        println!(Colour::Green, "{}", done);
+    }
+}
+
+/// Sync state of local paths, relative ones are taken from the current directory
+pub fn path_state(paths: &[String]) {
+    let current = env::current_dir().unwrap_or_default();
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|path| current.join(path).to_string_lossy().to_string())
+        .collect();
+
+    let Some(states) = request("POST", "paths", Some(serde_json::json!({ "paths": paths }))) else {
+        return;
+    };
+
+    for state in states.as_array().into_iter().flatten() {
+        println!(
+            Colour::Cyan,
+            "{:<10} {}",
+            state["state"].as_str().unwrap_or_default(),
+            state["path"].as_str().unwrap_or_default()
+        );
+    }
+}
@@ -5,16 +5,20 @@ use std::{
 
 pub mod colour;
 pub mod config;
+pub mod daemon;
 pub mod login_handler;
 
 use chrono::{Local, TimeZone};
+use common::config::take_config_arg;
 use login_handler::read_password;
 use serde_json::Value;
 
 use colour::Colour;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // Only picks which sync client to talk to, `daemon` looks it up again itself
+    take_config_arg(&mut args);
 
     if args.len() < 2 {
         println!(Colour::Red, "Error: no command provided.");
@@ -49,6 +53,18 @@ fn main() {
             println!(Colour::Cyan, "rename - Rename a file or directory.");
             println!(Colour::Cyan, "mv - Move a file or directory.");
             println!(Colour::Cyan, "cp - Copy a file or directory.");
+            println!(Colour::Cyan, "status - Show what the sync client is doing.");
+            println!(Colour::Cyan, "queue - List local changes waiting to be synced.");
+            println!(Colour::Cyan, "errors - List the sync client's recent errors.");
+            println!(Colour::Cyan, "retry - Retry a failed operation from 'errors'.");
+            println!(Colour::Cyan, "pause - Pause syncing.");
+            println!(Colour::Cyan, "resume - Resume syncing.");
+            println!(Colour::Cyan, "sync - Sync now.");
+            println!(Colour::Cyan, "state - Show the sync state of files.");
+            println!(
+                Colour::Gray,
+                "--config <path> - Talk to the sync client using this config file, also read from DRIVE_SYNC_CONFIG."
+            );
         }
 
         "help" | "?" | "h" => {
@@ -129,6 +145,36 @@ fn main() {
             copy_entry();
         }
 
+        "status" => daemon::status(),
+
+        "queue" => daemon::queue(),
+
+        "errors" => daemon::errors(),
+
+        "retry" => {
+            let Some(id) = args.get(2).and_then(|id| id.parse().ok()) else {
+                println!(Colour::Red, "Error: no error id provided.");
+                return;
+            };
+
+            daemon::retry(id);
+        }
+
+        "pause" => daemon::command("pause", "Syncing paused."),
+
+        "resume" => daemon::command("resume", "Syncing resumed."),
+
+        "sync" => daemon::command("sync", "Sync started."),
+
+        "state" => {
+            if args.len() < 3 {
+                println!(Colour::Red, "Error: no path provided.");
+                return;
+            }
+
+            daemon::path_state(&args[2..]);
+        }
+
         _ => {
             println!(Colour::Red, "Error: unknown command '{}'.", args[1]);
         }
@@ -4,10 +4,11 @@ use axum::{
     http::StatusCode,
     response::IntoResponse,
 };
+use common::EventKind;
 use std::{fs};
 
 use crate::{
-    AppState, routes::{auth::AuthUser, db::delete_shared_file}, util::{get_user_path, log_actions},
+    AppState, routes::{auth::AuthUser, db::delete_shared_file}, util::{get_user_path, log_actions, notify_sync, sync_path},
 };
 
 pub async fn delete_file(
@@ -15,7 +16,8 @@ pub async fn delete_file(
     Path(target_path): Path<String>,
 ) -> impl IntoResponse {
     let user_id = claims.user.clone();
-    let mut path = get_user_path(claims.user);
+    let root = get_user_path(claims.user);
+    let mut path = root.clone();
     path.push(&target_path);
 
     if !path.exists() {
@@ -32,6 +34,12 @@ pub async fn delete_file(
 
     match result {
         Ok(_) => {
+            notify_sync(
+                user_id.clone(),
+                EventKind::Delete {
+                    path: sync_path(&root, &path),
+                },
+            );
             log_actions(user_id, "delete".to_string(), target_path);
             (StatusCode::OK, "Deleted successfully").into_response()
         }
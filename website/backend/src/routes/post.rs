@@ -11,6 +11,7 @@ use axum::{
     response::IntoResponse,
 };
 
+use common::EventKind;
 use serde::Deserialize;
 use serde_json::Value;
 use uuid::Uuid;
@@ -22,7 +23,7 @@ use crate::{
         db::{change_shared_file_path, check_shared_file_exists, create_shared_file},
         get::get_directory_size,
     },
-    util::{MAX_STORAGE_BYTES, get_user_path, log_actions},
+    util::{MAX_STORAGE_BYTES, get_user_path, log_actions, notify_sync, sync_path},
 };
 
 pub async fn upload_root(
@@ -108,6 +109,7 @@ pub async fn create_file(
                 .into_response();
         }
 
+    let mut uploaded = Vec::new();
     let mut used_bytes = match get_directory_size(upload_root.clone()) {
         Ok(size) => size,
         Err(_) => {
@@ -159,6 +161,12 @@ pub async fn create_file(
 
             used_bytes += chunk_len;
         }
+
+        uploaded.push(sync_path(&upload_root, &final_path));
+    }
+
+    for path in uploaded {
+        notify_sync(user_id.clone(), EventKind::Modify { path });
     }
 
     log_actions(
@@ -188,12 +196,16 @@ pub async fn create_path(
     let user_id = claims.user.clone();
 
     let upload_root = get_user_path(claims.user);
-    let mut path_buf = upload_root;
+    let mut path_buf = upload_root.clone();
     path_buf.push(full_path.trim_start_matches('/'));
+    let path = sync_path(&upload_root, &path_buf);
 
     if full_path.ends_with('/') {
         match fs::create_dir_all(&path_buf) {
-            Ok(_) => (StatusCode::OK, "Folder created successfully").into_response(),
+            Ok(_) => {
+                notify_sync(user_id, EventKind::Mkdir { path });
+                (StatusCode::OK, "Folder created successfully").into_response()
+            }
             Err(_) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create folder").into_response()
             }
@@ -201,6 +213,7 @@ pub async fn create_path(
     } else {
         match fs::File::create(&path_buf) {
             Ok(_) => {
+                notify_sync(user_id.clone(), EventKind::Create { path });
                 log_actions(
                     user_id,
                     "create_file".into(),
@@ -289,6 +302,13 @@ pub async fn rename_path(
 
     match fs::rename(&old_full, &new_full) {
         Ok(_) => {
+            notify_sync(
+                user_id.clone(),
+                EventKind::Rename {
+                    from: sync_path(&upload_root, &old_full),
+                    to: sync_path(&upload_root, &new_full),
+                },
+            );
             log_actions(
                 user_id,
                 "rename".into(),
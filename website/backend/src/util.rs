@@ -5,13 +5,17 @@ use std::{
     sync::OnceLock,
 };
 
+use common::{Envelope, Event, EventKind};
 use sqlx::SqlitePool;
 
+use crate::routes::auth::generate_jwt;
+
 pub static UPLOAD_DIR: OnceLock<String> = OnceLock::new();
 pub static LOG_FILE: OnceLock<String> = OnceLock::new();
 pub const MAX_STORAGE_BYTES: u64 = 100 * 1024 * 1024 * 1024;
 pub static JWT_SECRET: OnceLock<String> = OnceLock::new();
 pub static JWT_DURATION_MINUTES: OnceLock<i64> = OnceLock::new();
+pub static SYNC_SERVER_URL: OnceLock<String> = OnceLock::new();
 
 pub fn clean_path(dir_path: String, user_id: String) -> Option<PathBuf> {
     let mut target_dir = get_user_path(user_id);
@@ -64,6 +68,49 @@ pub fn initialize_config() {
                 .expect("Invalid JWT_DURATION_MINUTES"),
         )
         .expect("Failed to set JWT_DURATION_MINUTES");
+
+    // Optional, without it changes made on the website only reach desktops on their next full scan
+    if let Ok(url) = std::env::var("SYNC_SERVER_URL") {
+        SYNC_SERVER_URL
+            .set(url.trim_end_matches('/').to_string())
+            .expect("Failed to set SYNC_SERVER_URL");
+    }
+}
+
+/// Records a change made through the website in the sync server's event log for `user_id`, which
+/// tells their connected desktop clients to pull it. Paths are relative to the user's folder.
+pub fn notify_sync(user_id: String, kind: EventKind) {
+    let Some(url) = SYNC_SERVER_URL.get() else {
+        return;
+    };
+
+    let body = Envelope::new(vec![Event::new(kind)]);
+    let token = generate_jwt(user_id, false);
+
+    tokio::spawn(async move {
+        let res = reqwest::Client::new()
+            .post(format!("{}/logs", url))
+            .bearer_auth(token)
+            .json(&body)
+            .send()
+            .await;
+
+        match res {
+            Ok(res) if res.status().is_success() => {}
+            Ok(res) => eprintln!("notify_sync: sync server answered {}", res.status()),
+            Err(e) => eprintln!("notify_sync: failed to reach sync server: {}", e),
+        }
+    });
+}
+
+/// `path` relative to `root` with `/` separators, how the sync server names files
+pub fn sync_path(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
 }
 
 pub fn log_actions(user_id: String, action: String, path: String) {